@@ -0,0 +1,78 @@
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::{
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+/// How often the secret challenges are derived from is replaced
+const ROTATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Challenge value clients send when asking for a challenge
+pub const NO_CHALLENGE: u32 = 0xFFFFFFFF;
+
+fn new_secret() -> [u8; 32] {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Issues and verifies challenges tied to the address that requested them
+pub struct Challenges {
+    current: [u8; 32],
+    previous: [u8; 32],
+    rotated: SystemTime,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Self {
+            current: new_secret(),
+            previous: new_secret(),
+            rotated: SystemTime::now(),
+        }
+    }
+
+    /// Replace the secret once it gets too old
+    fn rotate(&mut self) {
+        let now = SystemTime::now();
+        if now.duration_since(self.rotated).unwrap_or_default() >= ROTATE_INTERVAL {
+            self.previous = self.current;
+            self.current = new_secret();
+            self.rotated = now;
+        }
+    }
+
+    /// Derive the challenge for an address from a secret
+    fn derive(secret: &[u8; 32], addr: &SocketAddr) -> u32 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_le_bytes());
+        let digest = mac.finalize().into_bytes();
+        let challenge = u32::from_le_bytes(digest[0..4].try_into().unwrap());
+        // Never hand out the value that asks for a new challenge
+        if challenge == NO_CHALLENGE {
+            0
+        } else {
+            challenge
+        }
+    }
+
+    /// Returns the challenge an address has to send back to us
+    pub fn issue(&mut self, addr: &SocketAddr) -> u32 {
+        self.rotate();
+        Self::derive(&self.current, addr)
+    }
+
+    /// Returns true if the challenge was issued to the address recently
+    pub fn verify(&mut self, addr: &SocketAddr, challenge: u32) -> bool {
+        self.rotate();
+        challenge == Self::derive(&self.current, addr)
+            || challenge == Self::derive(&self.previous, addr)
+    }
+}
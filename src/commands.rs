@@ -0,0 +1,147 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::{
+    matchmaking::{
+        preferred_pick, preferred_region, queue_position, rank_servers, MatchmakingConfig,
+    },
+    poller::StatusCache,
+    Client, ClientState,
+};
+
+/// Show every server the client could be sent to
+fn list(config: &MatchmakingConfig, status: &StatusCache) -> String {
+    let candidates = rank_servers(config, status);
+    if candidates.is_empty() {
+        return "No servers are available right now\n".to_string();
+    }
+    let mut message = "Servers, best first:\n".to_string();
+    for (i, c) in candidates.iter().enumerate() {
+        message.push_str(&format!(
+            "{}. {} [{}] {} ({}) on {}, score {}, {} free\n",
+            i + 1,
+            c.key,
+            c.region.as_deref().unwrap_or("?"),
+            c.name,
+            c.addr,
+            c.map,
+            c.score,
+            c.free
+        ));
+    }
+    message
+}
+
+/// Choose a server to be sent to, by its key or name
+fn pick(config: &MatchmakingConfig, client: &mut Client, arg: Option<&str>) -> String {
+    let arg = match arg {
+        Some(arg) => arg,
+        None => return "Usage: mm_pick <server|any>, see mm_list for the servers\n".to_string(),
+    };
+    // Remember "any" so it overrides an mm_pick from the client's autoexec
+    if arg == "any" {
+        client.set_pick(Some(arg.to_string()));
+        return "You will be sent to the best server\n".to_string();
+    }
+    let found = config.servers().iter().find(|(key, server)| {
+        key.eq_ignore_ascii_case(arg) || server.name().is_some_and(|n| n.eq_ignore_ascii_case(arg))
+    });
+    match found {
+        Some((key, _)) => {
+            client.set_pick(Some(key.clone()));
+            format!("You will be sent to {} if it has room\n", key)
+        }
+        None => format!("There is no server called {}, see mm_list\n", arg),
+    }
+}
+
+/// Choose the region to be sent to
+fn region(config: &MatchmakingConfig, client: &mut Client, arg: Option<&str>) -> String {
+    let mut regions: Vec<&str> = config
+        .servers()
+        .values()
+        .filter_map(|s| s.region())
+        .collect();
+    regions.sort_unstable();
+    regions.dedup();
+    let arg = match arg {
+        Some(arg) => arg.to_lowercase(),
+        None => return format!("Usage: mm_region <{}|any>\n", regions.join("|")),
+    };
+    if arg == "any" {
+        client.set_region(Some(arg));
+        return "You will be sent to servers in any region\n".to_string();
+    }
+    if !regions.contains(&arg.as_str()) {
+        return format!("Unknown region, choose from {}\n", regions.join(", "));
+    }
+    let message = format!(
+        "You will be sent to servers in {} while they have room\n",
+        arg
+    );
+    client.set_region(Some(arg));
+    message
+}
+
+/// Describe where the client is in the queue and what it asked for
+fn status(
+    config: &MatchmakingConfig,
+    clients: &HashMap<SocketAddr, Client>,
+    addr: SocketAddr,
+) -> Option<String> {
+    let (position, queue) = queue_position(clients, addr);
+    let client = clients.get(&addr)?;
+    Some(format!(
+        "You are number {} of {} in the queue\n\
+         Party: {}\n\
+         Region: {}\n\
+         Picked server: {}\n\
+         Maps: {}\n\
+         Skipped servers: {}\n",
+        position,
+        queue,
+        client.party().unwrap_or_else(|| "none".to_string()),
+        preferred_region(config, client).unwrap_or_else(|| "any".to_string()),
+        preferred_pick(client).unwrap_or_else(|| "none".to_string()),
+        client.convar("mm_map").unwrap_or("any"),
+        client.skip()
+    ))
+}
+
+/// Run a console command sent by a client, returning what to print back to it
+pub fn run_command(
+    config: &MatchmakingConfig,
+    cache: &StatusCache,
+    clients: &mut HashMap<SocketAddr, Client>,
+    addr: SocketAddr,
+    command: &str,
+) -> Option<String> {
+    let mut args = command.split_whitespace();
+    let name = args.next()?;
+    let arg = args.next();
+    let message = match name {
+        "mm_list" => list(config, cache),
+        // Nothing looks at the preferences of a client that was already sent away
+        "mm_party" | "mm_pick" | "mm_region" | "mm_skip"
+            if clients.get(&addr)?.state == ClientState::Redirected =>
+        {
+            "You have already been sent to a server\n".to_string()
+        }
+        "mm_party" => match arg {
+            Some(code) => {
+                clients.get_mut(&addr)?.set_party(code.to_string());
+                format!("Queueing with party {}\n", code)
+            }
+            None => "Usage: mm_party <code>\n".to_string(),
+        },
+        "mm_pick" => pick(config, clients.get_mut(&addr)?, arg),
+        "mm_region" => region(config, clients.get_mut(&addr)?, arg),
+        "mm_skip" => {
+            let client = clients.get_mut(&addr)?;
+            client.skip_server();
+            format!("Passing over the {} best server(s)\n", client.skip())
+        }
+        "mm_status" => status(config, clients, addr)?,
+        _ => return None,
+    };
+    Some(message)
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+    net::IpAddr,
+};
+use toml::{value::Table, Value};
+
+/// The largest bias a server may be given in either direction
+const MAX_BIAS: i64 = 100;
+
+/// The highest tickrate a server may claim to run at
+const MAX_TICKRATE: i64 = 1000;
+
+/// The longest any matchmaking timing may be, which keeps deadlines from overflowing
+const MAX_SECONDS: i64 = 24 * 60 * 60;
+
+/// The shortest tick interval, below which the ticker would do little but spin
+const MIN_TICK_INTERVAL: f64 = 0.01;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Unable to read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "Unable to parse config: {}", e),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Config has {} problem(s):", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+/// Check an IP address field, returning the address if it is valid
+fn check_ip(section: &str, table: &Table, problems: &mut Vec<String>) -> Option<IpAddr> {
+    match table.get("address") {
+        Some(Value::String(address)) => match address.parse() {
+            Ok(address) => Some(address),
+            Err(_) => {
+                problems.push(format!("{}: \"{}\" is not a valid IP", section, address));
+                None
+            }
+        },
+        Some(_) => {
+            problems.push(format!("{}: address must be a string", section));
+            None
+        }
+        None => {
+            problems.push(format!("{}: missing address", section));
+            None
+        }
+    }
+}
+
+/// Returns true if the string could be a DNS name
+fn is_hostname(host: &str) -> bool {
+    // Anything made up of only digits and dots was meant to be an IPv4 address
+    !host.is_empty()
+        && host.len() <= 253
+        && !host.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Check a hostname or IP address field, returning the host if it is valid
+fn check_host(section: &str, table: &Table, problems: &mut Vec<String>) -> Option<String> {
+    match table.get("address") {
+        Some(Value::String(address)) => {
+            if address.parse::<IpAddr>().is_ok() || is_hostname(address) {
+                Some(address.to_lowercase())
+            } else {
+                problems.push(format!(
+                    "{}: \"{}\" is not a valid IP or hostname",
+                    section, address
+                ));
+                None
+            }
+        }
+        Some(_) => {
+            problems.push(format!("{}: address must be a string", section));
+            None
+        }
+        None => {
+            problems.push(format!("{}: missing address", section));
+            None
+        }
+    }
+}
+
+/// Check a port field, returning the port if it is valid
+fn check_port(section: &str, table: &Table, problems: &mut Vec<String>) -> Option<u16> {
+    match table.get("port") {
+        Some(Value::Integer(port)) if *port > 0 && *port <= u16::MAX as i64 => Some(*port as u16),
+        Some(_) => {
+            problems.push(format!("{}: port must be between 1 and 65535", section));
+            None
+        }
+        None => {
+            problems.push(format!("{}: missing port", section));
+            None
+        }
+    }
+}
+
+/// Check an optional number field against the range it must be in
+fn check_number(
+    section: &str,
+    table: &Table,
+    name: &str,
+    valid: fn(f64) -> bool,
+    description: &str,
+    problems: &mut Vec<String>,
+) {
+    let value = match table.get(name) {
+        Some(Value::Float(value)) => *value,
+        Some(Value::Integer(value)) => *value as f64,
+        Some(_) => {
+            problems.push(format!("{}: {} must be a number", section, name));
+            return;
+        }
+        None => return,
+    };
+    // TOML allows nan and inf, which would break the arithmetic done with these
+    if !value.is_finite() || !valid(value) {
+        problems.push(format!("{}: {} must be {}", section, name, description));
+    }
+}
+
+/// Check an optional whole number of seconds against the shortest it may be
+fn check_seconds(
+    section: &str,
+    table: &Table,
+    name: &str,
+    minimum: i64,
+    problems: &mut Vec<String>,
+) {
+    match table.get(name) {
+        Some(Value::Integer(seconds)) if *seconds >= minimum && *seconds <= MAX_SECONDS => {}
+        Some(_) => problems.push(format!(
+            "{}: {} must be between {} and {} seconds",
+            section, name, minimum, MAX_SECONDS
+        )),
+        None => {}
+    }
+}
+
+/// Find every problem with a parsed config file
+pub fn validate(config: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match config.get("limits") {
+        Some(Value::Table(table)) => {
+            check_number(
+                "limits",
+                table,
+                "burst",
+                |v| v >= 1.0,
+                "at least 1",
+                &mut problems,
+            );
+            check_number(
+                "limits",
+                table,
+                "packets_per_second",
+                |v| v > 0.0,
+                "more than 0",
+                &mut problems,
+            );
+        }
+        Some(_) => problems.push("limits: must be a table".to_string()),
+        None => {}
+    }
+
+    match config.get("matchmaking") {
+        Some(Value::Table(table)) => {
+            check_ip("matchmaking", table, &mut problems);
+            check_port("matchmaking", table, &mut problems);
+            if !matches!(table.get("hostname"), Some(Value::String(_))) {
+                problems.push("matchmaking: missing hostname".to_string());
+            }
+            // Zero would expire every client on each sweep or spin the timers
+            for name in &["idle_timeout", "poll_interval"] {
+                check_seconds("matchmaking", table, name, 1, &mut problems);
+            }
+            for name in &[
+                "decision_window",
+                "party_window",
+                "redirect_grace",
+                "redirect_linger",
+                "reservation_hold",
+            ] {
+                check_seconds("matchmaking", table, name, 0, &mut problems);
+            }
+            check_number(
+                "matchmaking",
+                table,
+                "tick_interval",
+                |v| v >= MIN_TICK_INTERVAL && v <= MAX_SECONDS as f64,
+                "between 0.01 and 86400 seconds",
+                &mut problems,
+            );
+        }
+        _ => problems.push("missing [matchmaking] section".to_string()),
+    }
+
+    match config.get("servers") {
+        Some(Value::Table(servers)) if servers.is_empty() => {
+            problems.push("servers: no servers are configured".to_string())
+        }
+        Some(Value::Table(servers)) => {
+            let mut seen: HashMap<(String, u16), &str> = HashMap::new();
+            let mut keys: Vec<&String> = servers.keys().collect();
+            keys.sort();
+            for key in keys {
+                let section = format!("servers.{}", key);
+                let table = match &servers[key] {
+                    Value::Table(table) => table,
+                    _ => {
+                        problems.push(format!("{}: must be a table", section));
+                        continue;
+                    }
+                };
+                let address = check_host(&section, table, &mut problems);
+                let port = check_port(&section, table, &mut problems);
+                match table.get("bias") {
+                    Some(Value::Integer(bias)) if bias.abs() <= MAX_BIAS => {}
+                    Some(_) => problems.push(format!(
+                        "{}: bias must be between -{} and {}",
+                        section, MAX_BIAS, MAX_BIAS
+                    )),
+                    None => problems.push(format!("{}: missing bias", section)),
+                }
+                match table.get("tickrate") {
+                    Some(Value::Integer(tickrate))
+                        if *tickrate > 0 && *tickrate <= MAX_TICKRATE => {}
+                    Some(_) => problems.push(format!(
+                        "{}: tickrate must be between 1 and {}",
+                        section, MAX_TICKRATE
+                    )),
+                    None => {}
+                }
+                if let (Some(address), Some(port)) = (address, port) {
+                    if let Some(other) = seen.insert((address.clone(), port), key) {
+                        problems.push(format!(
+                            "{}: {} port {} is already used by servers.{}",
+                            section, address, port, other
+                        ));
+                    }
+                }
+            }
+        }
+        _ => problems.push("missing [servers] section".to_string()),
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(matchmaking: &str) -> Vec<String> {
+        let config = format!(
+            "[matchmaking]\naddress = \"0.0.0.0\"\nhostname = \"Test\"\nport = 27015\n{}\n\
+             [servers.one]\naddress = \"127.0.0.1\"\nport = 27016\nbias = 0\n",
+            matchmaking
+        );
+        validate(&toml::de::from_str(&config).unwrap())
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(problems(""), Vec::<String>::new());
+        assert_eq!(
+            problems("decision_window = 0\nidle_timeout = 1\nredirect_linger = 0"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn timings_out_of_range() {
+        assert_eq!(
+            problems("idle_timeout = 0"),
+            vec!["matchmaking: idle_timeout must be between 1 and 86400 seconds"]
+        );
+        assert_eq!(
+            problems("poll_interval = 0\nredirect_linger = -1"),
+            vec![
+                "matchmaking: poll_interval must be between 1 and 86400 seconds",
+                "matchmaking: redirect_linger must be between 0 and 86400 seconds",
+            ]
+        );
+        assert_eq!(
+            problems("party_window = 1e9\ndecision_window = 100000"),
+            vec![
+                "matchmaking: decision_window must be between 0 and 86400 seconds",
+                "matchmaking: party_window must be between 0 and 86400 seconds",
+            ]
+        );
+        assert_eq!(
+            problems("redirect_grace = \"15\""),
+            vec!["matchmaking: redirect_grace must be between 0 and 86400 seconds"]
+        );
+    }
+
+    #[test]
+    fn fractional_tick_interval() {
+        assert_eq!(problems("tick_interval = 0.25"), Vec::<String>::new());
+        assert_eq!(problems("tick_interval = 2"), Vec::<String>::new());
+        for bad in &["0", "-1", "0.001", "nan", "inf", "\"1\""] {
+            assert_eq!(
+                problems(&format!("tick_interval = {}", bad)).len(),
+                1,
+                "tick_interval = {}",
+                bad
+            );
+        }
+    }
+}
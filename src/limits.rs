@@ -0,0 +1,146 @@
+use log::warn;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use crate::Client;
+
+/// How often dropped packets are reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Thresholds that protect the listener from floods
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Packets each address may send per second
+    pub packets_per_second: f64,
+    /// Packets each address may send at once before being limited
+    pub burst: f64,
+    /// Clients that may be connected at once
+    pub max_clients: usize,
+    /// Clients that may be connected at once from the same address
+    pub max_clients_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            packets_per_second: 60.0,
+            burst: 120.0,
+            max_clients: 512,
+            max_clients_per_ip: 4,
+        }
+    }
+}
+
+impl Limits {
+    /// Returns true if another client may connect from the address
+    pub fn admits(&self, clients: &HashMap<SocketAddr, Client>, addr: &SocketAddr) -> bool {
+        clients.len() < self.max_clients
+            && clients.keys().filter(|a| a.ip() == addr.ip()).count() < self.max_clients_per_ip
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+}
+
+/// Token bucket rate limiter keyed by source address
+pub struct RateLimiter {
+    buckets: HashMap<IpAddr, Bucket>,
+    dropped: u64,
+    reported: SystemTime,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            dropped: 0,
+            reported: SystemTime::now(),
+        }
+    }
+
+    /// Take a token from the address's bucket, returning false if it is empty
+    pub fn allow(&mut self, limits: &Limits, ip: IpAddr) -> bool {
+        let now = SystemTime::now();
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: limits.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).unwrap_or_default();
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * limits.packets_per_second).min(limits.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+
+    /// Forget about quiet addresses and log how many packets were dropped, called on every tick
+    pub fn report(&mut self, limits: &Limits) {
+        let now = SystemTime::now();
+        // A bucket that would have refilled by now is the same as no bucket at all
+        let refill = Duration::from_secs_f64(limits.burst / limits.packets_per_second.max(0.001));
+        self.buckets
+            .retain(|_, b| now.duration_since(b.updated).unwrap_or_default() < refill);
+        if now.duration_since(self.reported).unwrap_or_default() < REPORT_INTERVAL {
+            return;
+        }
+        if self.dropped > 0 {
+            warn!(
+                "Dropped {} packets from rate limited addresses in the last {} seconds",
+                self.dropped,
+                REPORT_INTERVAL.as_secs()
+            );
+        }
+        self.dropped = 0;
+        self.reported = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn burst_then_dropped() {
+        let limits = Limits {
+            packets_per_second: 0.001,
+            burst: 3.0,
+            ..Limits::default()
+        };
+        let mut limiter = RateLimiter::new();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        assert!((0..3).all(|_| limiter.allow(&limits, ip)));
+        assert!(!limiter.allow(&limits, ip));
+        assert!(limiter.allow(&limits, IpAddr::from([127, 0, 0, 2])));
+        assert_eq!(limiter.dropped, 1);
+    }
+
+    #[test]
+    fn refilled_buckets_are_pruned_before_the_report() {
+        let limits = Limits {
+            packets_per_second: 100.0,
+            burst: 1.0,
+            ..Limits::default()
+        };
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.allow(&limits, IpAddr::from([127, 0, 0, 1])));
+        assert!(!limiter.allow(&limits, IpAddr::from([127, 0, 0, 1])));
+        thread::sleep(Duration::from_millis(50));
+        limiter.report(&limits);
+        assert!(limiter.buckets.is_empty());
+        // Drops are still counted until the report is due
+        assert_eq!(limiter.dropped, 1);
+    }
+}
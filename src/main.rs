@@ -1,43 +1,98 @@
 use ::chrono::Local;
 use ::fern::Dispatch;
-use ::log::{error, LevelFilter};
+use ::log::{debug, error, LevelFilter};
 use std::{
     collections::HashMap,
     convert::TryInto,
+    env,
     error::Error,
-    fs::File,
-    io::{self, Read},
+    io,
     net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{Arc, RwLock},
     time::SystemTime,
 };
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, time};
 
 use snap::raw::Decoder;
 
+mod challenge;
+
+mod commands;
+use challenge::Challenges;
+
+mod config;
+
+mod limits;
+use limits::RateLimiter;
+
 mod matchmaking;
-use matchmaking::{matchmaking_tick, MatchmakingConfig};
+use matchmaking::{matchmaking_tick, MatchmakingConfig, MatchmakingState};
+
+mod motd;
+
+mod poller;
+use poller::{spawn_poller, StatusCache};
+
+mod reload;
+use reload::spawn_watcher;
+
+mod scoring;
 
 mod stateless;
 use stateless::handle_stateless;
 
+mod split;
+use split::SplitPackets;
+
 mod stateful;
-use stateful::{handle_stateful, messages::Messages};
+use stateful::{flush_queued, handle_stateful, messages::Messages};
 
 pub struct NetChannel {
+    compressed: Option<usize>, // Uncompressed size of the block, if compressed
+    file: Option<u32>,         // Transfer id of a file being refused
     fragments: Vec<Vec<u8>>,
     num_fragments: usize,
     length: usize,
 }
 
+/// Reliable data waiting to be acknowledged by the client
+pub struct SubChannel {
+    data: Vec<u8>,
+    start: usize, // First fragment of the current send
+    count: usize, // Fragments in the current send
+    send_seq: u32,
+    sent: bool, // False until the data goes out, and again once it was lost
+}
+
 pub struct Client {
+    challenge: Option<u32>,
+    convars: HashMap<String, String>, // Userinfo the client has sent us
+    in_seq: u32,
     joined: SystemTime,
-    name: Option<String>,
+    last_packet: SystemTime,
     netchannels: [NetChannel; 2],
+    out_reliable: u8,
+    out_seq: u32,
+    party: Option<String>,
+    pick: Option<String>,
     queued: Vec<Messages>,
+    redirected: Option<SystemTime>,
+    region: Option<String>,
     reliable: u8,
+    sending: Option<SubChannel>,
+    signon: u8,
+    skip: usize,
     pub state: ClientState,
 }
 
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Client {
     /// Flip a bit in the reliable state
     pub fn flip_rel(&mut self, n: usize) {
@@ -49,25 +104,57 @@ impl Client {
         self.joined
     }
 
+    /// Return the time the last packet was received from the client
+    pub fn last_packet(&self) -> SystemTime {
+        self.last_packet
+    }
+
     /// Returns the name of the client
     pub fn name(&self) -> Option<String> {
-        self.name.clone()
+        self.convar("name").map(str::to_string)
+    }
+
+    /// Returns the party code the client is queueing with
+    pub fn party(&self) -> Option<String> {
+        self.party.clone()
+    }
+
+    /// Returns the key of the server the client asked to be sent to
+    pub fn pick(&self) -> Option<String> {
+        self.pick.clone()
     }
 
     /// Create a new client state
     pub fn new() -> Self {
+        let now = SystemTime::now();
         Self {
-            joined: SystemTime::now(),
-            name: None,
+            challenge: None,
+            convars: HashMap::new(),
+            in_seq: 0,
+            joined: now,
+            last_packet: now,
+            out_reliable: 0,
+            out_seq: 0,
+            party: None,
+            pick: None,
             queued: vec![],
+            redirected: None,
+            region: None,
             reliable: 0,
+            sending: None,
+            signon: 0,
+            skip: 0,
             netchannels: [
                 NetChannel {
+                    compressed: None,
+                    file: None,
                     fragments: vec![],
                     num_fragments: 0,
                     length: 0,
                 },
                 NetChannel {
+                    compressed: None,
+                    file: None,
                     fragments: vec![],
                     num_fragments: 0,
                     length: 0,
@@ -77,9 +164,63 @@ impl Client {
         }
     }
 
-    /// Sets the name of the client
-    pub fn set_name(&mut self, name: String) {
-        self.name = Some(name);
+    /// Return the time the client was redirected
+    pub fn redirected(&self) -> Option<SystemTime> {
+        self.redirected
+    }
+
+    /// Returns the region the client prefers
+    pub fn region(&self) -> Option<String> {
+        self.region.clone()
+    }
+
+    /// Marks the client as redirected to another server
+    pub fn set_redirected(&mut self) {
+        self.state = ClientState::Redirected;
+        self.redirected = Some(SystemTime::now());
+    }
+
+    /// Returns a convar the client has set, unless it was left empty
+    pub fn convar(&self, name: &str) -> Option<&str> {
+        self.convars
+            .get(name)
+            .map(String::as_str)
+            .filter(|v| !v.is_empty())
+    }
+
+    /// Record convars sent by the client, which only sends the ones that changed after connecting
+    pub fn set_convars(&mut self, convars: HashMap<String, String>) {
+        self.convars.extend(convars);
+    }
+
+    /// Sets the party code of the client
+    pub fn set_party(&mut self, party: String) {
+        self.party = Some(party);
+    }
+
+    /// Sets the server the client asked to be sent to
+    pub fn set_pick(&mut self, pick: Option<String>) {
+        self.pick = pick;
+    }
+
+    /// Sets the region the client prefers
+    pub fn set_region(&mut self, region: Option<String>) {
+        self.region = region;
+    }
+
+    /// Returns how many of the best servers the client wants to pass over
+    pub fn skip(&self) -> usize {
+        self.skip
+    }
+
+    /// Pass over one more of the best servers
+    pub fn skip_server(&mut self) {
+        self.skip += 1;
+    }
+
+    /// Record that a packet was just received from the client
+    pub fn touch(&mut self) {
+        self.last_packet = SystemTime::now();
     }
 }
 
@@ -91,9 +232,30 @@ pub enum ClientState {
     Redirected, // The client has been redirected to another server.
 }
 
+/// Forget about clients that went quiet or have had time to follow their redirect
+fn expire_clients(config: &MatchmakingConfig, clients: &mut HashMap<SocketAddr, Client>) {
+    let now = SystemTime::now();
+    clients.retain(|addr, client| {
+        let idle = now.duration_since(client.last_packet()).unwrap_or_default();
+        if idle >= config.idle_timeout() {
+            debug!("{} timed out after {} seconds", addr, idle.as_secs());
+            return false;
+        }
+        if let Some(redirected) = client.redirected() {
+            if now.duration_since(redirected).unwrap_or_default() >= config.redirect_linger() {
+                debug!("{} was redirected, forgetting about them", addr);
+                return false;
+            }
+        }
+        true
+    });
+}
+
 async fn handle_request(
     config: &MatchmakingConfig,
+    status: &StatusCache,
     clients: &mut HashMap<SocketAddr, Client>,
+    challenges: &mut Challenges,
     sock: &mut UdpSocket,
     addr: SocketAddr,
     data: &[u8],
@@ -101,13 +263,23 @@ async fn handle_request(
     if data.len() > 4 {
         let header = u32::from_le_bytes(data[0..4].try_into().unwrap());
         if header == 0xFFFFFFFF {
-            handle_stateless(config, sock, addr, data).await?;
+            handle_stateless(config, status, clients, challenges, sock, addr, data).await?;
         } else if header == 0xFFFFFFFD {
             let mut decompressor = Decoder::new();
             let decompressed = decompressor.decompress_vec(&data[8..])?;
-            handle_stateful(clients, sock, addr, &decompressed).await;
+            handle_stateful(
+                config,
+                status,
+                clients,
+                challenges,
+                sock,
+                addr,
+                &decompressed,
+            )
+            .await;
         } else if header != 0xFFFFFFFE {
-            handle_stateful(clients, sock, addr, data).await;
+            // Split packets never contain other split packets
+            handle_stateful(config, status, clients, challenges, sock, addr, data).await;
         }
     }
     Ok(())
@@ -133,22 +305,95 @@ async fn main() {
         .chain(io::stderr())
         .apply()
         .unwrap();
-    let mut mm_config = File::open("matchmaking.toml").unwrap();
-    let mut buffer = String::new();
-    mm_config.read_to_string(&mut buffer).unwrap();
-    let config = ::toml::de::from_str::<MatchmakingConfig>(&buffer).unwrap();
+    let mut path = PathBuf::from("matchmaking.toml");
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => match args.next() {
+                Some(p) => path = PathBuf::from(p),
+                None => {
+                    error!("--config requires a path");
+                    process::exit(2);
+                }
+            },
+            _ => {
+                error!("Unknown argument: {}", arg);
+                process::exit(2);
+            }
+        }
+    }
+    let config = match MatchmakingConfig::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    let mut sock = match UdpSocket::bind(config.bind_addr()).await {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("Unable to bind to {}: {}", config.bind_addr(), e);
+            process::exit(1);
+        }
+    };
+    let shared = Arc::new(RwLock::new(Arc::new(config)));
+    spawn_watcher(path, shared.clone());
+    let status = spawn_poller(shared.clone());
     let mut clients = HashMap::<SocketAddr, Client>::new();
-    let mut sock = UdpSocket::bind(config.bind_addr()).await.unwrap();
-    let mut last_tick = SystemTime::now();
+    let mut mm_state = MatchmakingState::new();
+    let mut splits = SplitPackets::new();
+    let mut challenges = Challenges::new();
+    let mut limiter = RateLimiter::new();
+    let mut tick_interval = shared.read().unwrap().tick_interval();
+    let mut ticker = time::interval(tick_interval);
     loop {
         let mut buffer = vec![0; 1400];
-        if let Ok((len, addr)) = sock.recv_from(&mut buffer).await {
-            if let Err(e) =
-                handle_request(&config, &mut clients, &mut sock, addr, &buffer[..len]).await
-            {
-                error!("{}", e);
+        let (len, addr) = tokio::select! {
+            result = sock.recv_from(&mut buffer) => match result {
+                Ok(received) => received,
+                Err(_) => continue,
+            },
+            _ = ticker.tick() => {
+                let config = shared.read().unwrap().clone();
+                expire_clients(&config, &mut clients);
+                matchmaking_tick(&config, &status, &mut mm_state, &mut clients);
+                flush_queued(&mut clients, &mut sock).await;
+                limiter.report(config.limits());
+                // Follow changes to the tick interval from reloads
+                if config.tick_interval() != tick_interval {
+                    tick_interval = config.tick_interval();
+                    ticker = time::interval_at(time::Instant::now() + tick_interval, tick_interval);
+                }
+                continue;
             }
-            matchmaking_tick(&config, &mut last_tick, &mut clients);
+        };
+        let config = shared.read().unwrap().clone();
+        if !limiter.allow(config.limits(), addr.ip()) {
+            continue;
+        }
+        buffer.truncate(len);
+        if len > 4 && u32::from_le_bytes(buffer[0..4].try_into().unwrap()) == 0xFFFFFFFE {
+            buffer = match splits.insert(addr, &buffer) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to reassemble split packet: {}", e);
+                    continue;
+                }
+            };
+        }
+        if let Err(e) = handle_request(
+            &config,
+            &status,
+            &mut clients,
+            &mut challenges,
+            &mut sock,
+            addr,
+            &buffer,
+        )
+        .await
+        {
+            error!("{}", e);
         }
     }
 }
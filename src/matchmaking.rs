@@ -1,74 +1,302 @@
-use a2s::A2SClient;
+use a2s::info::Info;
 use log::{info, warn};
 use serde::Deserialize;
 use std::{
     collections::HashMap,
     error::Error,
     fmt::{self, Display, Formatter},
-    net::{Ipv4Addr, SocketAddr},
-    time::SystemTime,
+    fs::File,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use crate::{stateful::messages::Messages, Client, ClientState};
+use crate::{
+    config::{validate, ConfigError},
+    limits::Limits,
+    poller::StatusCache,
+    scoring::{ScoringOverride, ScoringRules},
+    stateful::messages::Messages,
+    Client, ClientState,
+};
+
+/// Rate a client needs for every tick of a server, about what the old default rate of 30000
+/// gives a 66 tick server
+const MIN_RATE_PER_TICK: u32 = 450;
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+fn default_decision_window() -> u64 {
+    5
+}
+
+fn default_idle_timeout() -> u64 {
+    30
+}
+
+fn default_map() -> String {
+    "itemtest".to_string()
+}
+
+fn default_max_players() -> u8 {
+    24
+}
+
+fn default_motd() -> String {
+    "Welcome to {hostname}!\n\
+     You are number {position} of {queue} in the queue.\n\
+     Best server right now: {best}\n\
+     Servers in the pool:\n\
+     {servers}\n\
+     Type mm_list, mm_pick, mm_region or mm_skip in the console to choose where you go."
+        .to_string()
+}
+
+fn default_party_window() -> u64 {
+    15
+}
+
+fn default_redirect_linger() -> u64 {
+    10
+}
+
+fn default_reservation_hold() -> u64 {
+    15
+}
+
+fn default_tick_interval() -> f64 {
+    1.0
+}
 
 #[derive(Deserialize)]
 struct GenericOptions {
-    address: Ipv4Addr,
+    address: IpAddr,
+    #[serde(default = "default_decision_window")]
+    decision_window: u64,
     hostname: String,
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+    #[serde(default = "default_map")]
+    map: String,
+    #[serde(default = "default_max_players")]
+    max_players: u8,
+    #[serde(default = "default_motd")]
+    motd: String,
+    #[serde(default = "default_party_window")]
+    party_window: u64,
+    #[serde(default = "default_poll_interval")]
+    poll_interval: u64,
     port: u16,
+    #[serde(default = "default_redirect_linger")]
+    redirect_linger: u64,
+    // Configs written before the rename still work
+    #[serde(alias = "redirect_grace", default = "default_reservation_hold")]
+    reservation_hold: u64,
+    #[serde(default = "default_tick_interval")]
+    tick_interval: f64,
 }
 
 impl GenericOptions {
-    pub fn bind_addr(&self) -> (Ipv4Addr, u16) {
-        (self.address, self.port)
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    pub fn decision_window(&self) -> Duration {
+        Duration::from_secs(self.decision_window)
     }
 
     pub fn hostname(&self) -> String {
         self.hostname.clone()
     }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn map(&self) -> String {
+        self.map.clone()
+    }
+
+    pub fn max_players(&self) -> u8 {
+        self.max_players
+    }
+
+    pub fn motd(&self) -> String {
+        self.motd.clone()
+    }
+
+    pub fn party_window(&self) -> Duration {
+        Duration::from_secs(self.party_window)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+
+    pub fn redirect_linger(&self) -> Duration {
+        Duration::from_secs(self.redirect_linger)
+    }
+
+    pub fn reservation_hold(&self) -> Duration {
+        Duration::from_secs(self.reservation_hold)
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.tick_interval)
+    }
 }
 
 #[derive(Deserialize)]
 pub struct MatchmakingConfig {
+    #[serde(default)]
+    languages: HashMap<String, String>,
+    #[serde(default)]
+    limits: Limits,
     matchmaking: GenericOptions,
+    #[serde(default)]
+    rules: HashMap<String, String>,
+    #[serde(default)]
+    scoring: ScoringRules,
     servers: HashMap<String, Server>,
 }
 
+/// The active config, swapped out whenever it is reloaded
+pub type SharedConfig = Arc<RwLock<Arc<MatchmakingConfig>>>;
+
 impl MatchmakingConfig {
-    pub fn bind_addr(&self) -> (Ipv4Addr, u16) {
+    /// Read and validate a config file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let mut file = File::open(path)?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)?;
+        // Look for every problem before the parser stops at the first one
+        let problems = validate(&::toml::de::from_str(&buffer)?);
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+        Ok(::toml::de::from_str::<Self>(&buffer)?)
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
         self.matchmaking.bind_addr()
     }
 
+    /// How long players have to use the console commands before they are sent to a server
+    pub fn decision_window(&self) -> Duration {
+        self.matchmaking.decision_window()
+    }
+
     pub fn hostname(&self) -> String {
         self.matchmaking.hostname()
     }
+
+    /// How long a client may go without sending a packet
+    pub fn idle_timeout(&self) -> Duration {
+        self.matchmaking.idle_timeout()
+    }
+
+    /// Regions players are sent to by the language their game is in
+    pub fn languages(&self) -> &HashMap<String, String> {
+        &self.languages
+    }
+
+    /// Thresholds that protect the listener from floods
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// The map clients load while they wait, which must ship with the game
+    pub fn map(&self) -> String {
+        self.matchmaking.map()
+    }
+
+    /// The max player count shown in the server browser
+    pub fn max_players(&self) -> u8 {
+        self.matchmaking.max_players()
+    }
+
+    /// Printed to clients as they connect, with placeholders for the queue and servers
+    pub fn motd(&self) -> String {
+        self.matchmaking.motd()
+    }
+
+    /// How long a party is held while waiting for its members
+    pub fn party_window(&self) -> Duration {
+        self.matchmaking.party_window()
+    }
+
+    /// How often the servers in the pool are queried
+    pub fn poll_interval(&self) -> Duration {
+        self.matchmaking.poll_interval()
+    }
+
+    /// How long a redirected client is kept around to receive the redirect
+    pub fn redirect_linger(&self) -> Duration {
+        self.matchmaking.redirect_linger()
+    }
+
+    /// How long a slot stays held for a redirected player, who should show up on the server by then
+    pub fn reservation_hold(&self) -> Duration {
+        self.matchmaking.reservation_hold()
+    }
+
+    /// How often the matchmaker assigns players to servers, which may be a fraction of a second
+    pub fn tick_interval(&self) -> Duration {
+        self.matchmaking.tick_interval()
+    }
+
+    /// Extra rules published through A2S_RULES
+    pub fn rules(&self) -> &HashMap<String, String> {
+        &self.rules
+    }
+
+    pub fn servers(&self) -> &HashMap<String, Server> {
+        &self.servers
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash)]
-struct Server {
-    address: Ipv4Addr,
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Server {
+    address: String, // Hostname or IP
+
     bias: isize,
     name: Option<String>,
     port: u16,
+    region: Option<String>,
+    #[serde(default)]
+    scoring: ScoringOverride,
+    tickrate: Option<u32>,
 }
 
 impl Server {
-    pub fn score(&self) -> Result<isize, Box<dyn Error>> {
-        // Query server info
-        let client = A2SClient::new().unwrap();
-        let info = client.info((self.address, self.port))?;
-        // Score the server based on certain criteria
-        let mut score = 0;
-        // Reward servers for having players but reject full servers
+    pub fn score(&self, rules: &ScoringRules, info: &Info) -> Result<isize, Box<dyn Error>> {
+        // Reject full servers outright
         if info.players >= info.max_players {
             return Err(Box::new(ServerError::ServerFull));
-        } else if info.players >= 6 {
-            score += info.players as isize;
         }
-        score -= (info.max_players as isize - 24).abs(); // Punish servers from straying from the 24 maxplayer limit
-        score -= info.bots as isize; // Remove one point per bot
-        score += self.bias; // Apply bias
-        Ok(score)
+        // Score the server based on the configured rules
+        let rules = rules.with_override(&self.scoring);
+        let score = rules.evaluate(info, self.region.as_deref()).round() as isize;
+        Ok(score + self.bias) // Apply bias
+    }
+
+    /// Returns the name the server is listed under, if one is configured
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the region the server is in
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Returns the host and port the server can be reached at
+    pub fn address(&self) -> (&str, u16) {
+        (&self.address, self.port)
     }
 }
 
@@ -89,41 +317,297 @@ impl Error for ServerError {
     }
 }
 
+/// A slot on a server held for a player who was just redirected to it
+struct Reservation {
+    server: String,
+    reserved: SystemTime,
+}
+
+/// State the matchmaker keeps between ticks
+pub struct MatchmakingState {
+    reservations: Vec<Reservation>,
+}
+
+impl MatchmakingState {
+    pub fn new() -> Self {
+        Self {
+            reservations: vec![],
+        }
+    }
+
+    /// Number of slots currently held on a server
+    fn reserved(&self, server: &str) -> usize {
+        self.reservations
+            .iter()
+            .filter(|r| r.server == server)
+            .count()
+    }
+
+    /// Release reservations once the server's player count should include the player
+    fn release(&mut self, config: &MatchmakingConfig, status: &StatusCache) {
+        let hold = config.reservation_hold();
+        let cache = status.read().unwrap();
+        self.reservations.retain(|r| match cache.get(&r.server) {
+            Some(status) => status.updated < r.reserved + hold,
+            None => false,
+        });
+    }
+}
+
+/// A pooled server that can currently accept players
+pub struct Candidate {
+    pub key: String,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub region: Option<String>,
+    pub map: String,
+    pub tickrate: Option<u32>,
+    pub score: isize,
+    pub free: usize,
+}
+
+/// Score every responding server in the pool, best first
+pub fn rank_servers(config: &MatchmakingConfig, status: &StatusCache) -> Vec<Candidate> {
+    // Snapshots older than a few polls belong to servers that stopped responding
+    let max_age = config.poll_interval() * 3;
+    let cache = status.read().unwrap();
+    let mut candidates = Vec::new();
+    for (key, server) in &config.servers {
+        if let Some(status) = cache.get(key).filter(|s| s.is_fresh(max_age)) {
+            if let Ok(score) = server.score(&config.scoring, &status.info) {
+                candidates.push(Candidate {
+                    key: key.clone(),
+                    name: server
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| status.info.name.clone()),
+                    addr: status.addr,
+                    region: server.region.clone(),
+                    map: status.info.map.clone(),
+                    tickrate: server.tickrate,
+                    score,
+                    free: status.info.max_players.saturating_sub(status.info.players) as usize,
+                });
+            }
+        }
+    }
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.score));
+    candidates
+}
+
+/// Returns a client's place in the queue and the length of the queue
+pub fn queue_position(clients: &HashMap<SocketAddr, Client>, addr: SocketAddr) -> (usize, usize) {
+    // Everyone still waiting, in the order they joined
+    let mut queue: Vec<(&SocketAddr, &Client)> = clients
+        .iter()
+        .filter(|(_, c)| c.state != ClientState::Redirected)
+        .collect();
+    queue.sort_by_key(|(_, c)| c.joined());
+    let position = queue
+        .iter()
+        .position(|(a, _)| **a == addr)
+        .map_or(queue.len(), |i| i + 1);
+    (position, queue.len())
+}
+
+/// Returns the server a client asked for with mm_pick, either in the console or their autoexec
+pub fn preferred_pick(client: &Client) -> Option<String> {
+    client
+        .pick()
+        .or_else(|| client.convar("mm_pick").map(str::to_string))
+        .filter(|pick| pick != "any")
+}
+
+/// Returns the region a client would like to play in, either chosen with mm_region or guessed
+/// from the language their game is in
+pub fn preferred_region(config: &MatchmakingConfig, client: &Client) -> Option<String> {
+    client
+        .region()
+        .or_else(|| client.convar("mm_region").map(str::to_lowercase))
+        .or_else(|| {
+            config
+                .languages()
+                .get(client.convar("cl_language")?)
+                .cloned()
+        })
+        .filter(|region| region != "any")
+}
+
+/// Returns true if a client's rates are high enough to keep up with a server's tickrate
+fn keeps_up(client: &Client, tickrate: u32) -> bool {
+    let rate = |name| client.convar(name).and_then(|v| v.parse::<u32>().ok());
+    // Rates the client didn't send can't hold it back
+    rate("cl_cmdrate").is_none_or(|r| r >= tickrate)
+        && rate("rate").is_none_or(|r| r >= tickrate * MIN_RATE_PER_TICK)
+}
+
+/// Keep only the servers a client would like, unless that leaves none of them
+fn narrow(servers: &mut Vec<usize>, wanted: impl Fn(usize) -> bool) {
+    if servers.iter().any(|i| wanted(*i)) {
+        servers.retain(|i| wanted(*i));
+    }
+}
+
+/// Find the server a client would like that has room for a number of players
+fn choose<'a>(
+    config: &MatchmakingConfig,
+    candidates: &'a mut [Candidate],
+    client: &Client,
+    needed: usize,
+) -> Option<&'a mut Candidate> {
+    let mut fits: Vec<usize> = (0..candidates.len())
+        .filter(|i| candidates[*i].free >= needed)
+        .collect();
+    // A server the client picked wins as long as it has room
+    if let Some(pick) = preferred_pick(client) {
+        let picked = fits.iter().copied().find(|i| {
+            let c = &candidates[*i];
+            c.key.eq_ignore_ascii_case(&pick) || c.name.eq_ignore_ascii_case(&pick)
+        });
+        if let Some(i) = picked {
+            return Some(&mut candidates[i]);
+        }
+    }
+    // Stay in the preferred region unless it has no room left
+    if let Some(region) = preferred_region(config, client) {
+        narrow(&mut fits, |i| {
+            candidates[i].region.as_deref() == Some(region.as_str())
+        });
+    }
+    // Maps are matched by prefix, so "pl_,koth_" asks for payload or king of the hill
+    if let Some(maps) = client.convar("mm_map").map(str::to_lowercase) {
+        narrow(&mut fits, |i| {
+            let map = candidates[i].map.to_lowercase();
+            maps.split(',')
+                .any(|m| !m.is_empty() && map.starts_with(m.trim()))
+        });
+    }
+    narrow(&mut fits, |i| {
+        candidates[i].tickrate.is_none_or(|t| keeps_up(client, t))
+    });
+    // Pass over the best servers the client skipped, as long as there are others
+    let i = *fits.get(client.skip().min(fits.len().checked_sub(1)?))?;
+    Some(&mut candidates[i])
+}
+
+/// What ties the members of a party together
+#[derive(Eq, Hash, PartialEq)]
+enum PartyKey {
+    Code(String),    // Members share a party code
+    Address(IpAddr), // Members joined from the same address
+}
+
+/// Returns how long ago a client joined
+fn waited(now: SystemTime, client: &Client) -> Duration {
+    now.duration_since(client.joined()).unwrap_or_default()
+}
+
+/// Redirect a client to a server and hold a slot for them
+fn redirect(state: &mut MatchmakingState, now: SystemTime, target: &Candidate, p: &mut Client) {
+    state.reservations.push(Reservation {
+        server: target.key.clone(),
+        reserved: now,
+    });
+    info!("Redirecting {:?} to {}", p.name(), target.addr);
+    // SocketAddr wraps IPv6 addresses in brackets so the port isn't mistaken for part of it
+    p.queued.push(Messages::NET_STRING_CMD {
+        command: format!("redirect {}", target.addr),
+    });
+    p.set_redirected();
+}
+
 pub fn matchmaking_tick(
     config: &MatchmakingConfig,
-    last: &mut SystemTime,
+    status: &StatusCache,
+    state: &mut MatchmakingState,
     clients: &mut HashMap<SocketAddr, Client>,
 ) {
     let now = SystemTime::now();
-    if now.duration_since(*last).unwrap().as_secs() >= 1 {
-        *last = now;
-        let mut scored = Vec::new();
-        let mut players: Vec<&mut Client> = clients.values_mut().collect();
-        players.sort_by(|a, b| b.joined().cmp(&a.joined()));
-        for server in config.servers.values() {
-            if let Ok(s) = server.score() {
-                scored.push((server, s));
+    state.release(config, status);
+    let window = config.party_window();
+    let decision = config.decision_window();
+    let mut parties: HashMap<PartyKey, Vec<&mut Client>> = HashMap::new();
+    for (addr, p) in clients.iter_mut() {
+        if p.state == ClientState::Redirected {
+            continue;
+        }
+        let key = match p.party() {
+            Some(code) => PartyKey::Code(code),
+            None => PartyKey::Address(addr.ip()),
+        };
+        parties.entry(key).or_default().push(p);
+    }
+    let mut ready: Vec<Vec<&mut Client>> = Vec::new();
+    for (key, members) in parties {
+        // Wait for members that are still joining
+        if members
+            .iter()
+            .any(|p| p.state == ClientState::Fresh && waited(now, p) < window)
+        {
+            continue;
+        }
+        // Leave time for mm_pick, mm_skip and the like to be typed before anyone is sent away
+        if members.iter().any(|p| waited(now, p) < decision) {
+            continue;
+        }
+        // Give the rest of a party time to show up
+        if let PartyKey::Code(_) = key {
+            if members.iter().any(|p| waited(now, p) < window) {
+                continue;
             }
         }
-        scored.sort_by(|a, b| b.1.cmp(&a.1));
-        let servers: Vec<&Server> = scored.iter().map(|v| v.0).collect();
-        if servers.len() > 0 {
-            for p in players {
-                if p.state == ClientState::Confirmed {
-                    info!(
-                        "Redirecting {:?} to {}:{}",
-                        p.name(),
-                        servers[0].address,
-                        servers[0].port
-                    );
-                    p.queued.push(Messages::SVC_STRING_CMD {
-                        command: format!("redirect {}:{}", servers[0].address, servers[0].port),
-                    });
-                    p.state = ClientState::Redirected;
+        let members: Vec<&mut Client> = members
+            .into_iter()
+            .filter(|p| p.state == ClientState::Confirmed)
+            .collect();
+        if !members.is_empty() {
+            ready.push(members);
+        }
+    }
+    if ready.is_empty() {
+        return;
+    }
+    // Parties who have waited the longest get first pick of the slots
+    for members in ready.iter_mut() {
+        members.sort_by_key(|p| p.joined());
+    }
+    ready.sort_by_key(|members| members[0].joined());
+    let mut candidates = rank_servers(config, status);
+    if candidates.is_empty() {
+        warn!("No servers to send players to!");
+        return;
+    }
+    for c in candidates.iter_mut() {
+        c.free = c.free.saturating_sub(state.reserved(&c.key));
+    }
+    for members in ready {
+        // Keep the party together on the best server that fits all of them, going by the
+        // preferences of whoever has waited the longest
+        if let Some(target) = choose(config, &mut candidates, members[0], members.len()) {
+            target.free -= members.len();
+            for p in members {
+                redirect(state, now, target, p);
+            }
+            continue;
+        }
+        if members.len() > 1 {
+            info!(
+                "No server fits a party of {}, splitting it up",
+                members.len()
+            );
+        }
+        for p in members {
+            // Spill over to the next best server once the best one is full
+            match choose(config, &mut candidates, p, 1) {
+                Some(target) => {
+                    target.free -= 1;
+                    redirect(state, now, target, p);
+                }
+                None => {
+                    warn!("No free slots left to send players to!");
+                    return;
                 }
             }
-        } else {
-            warn!("No servers to send players to!");
         }
     }
 }
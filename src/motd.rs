@@ -0,0 +1,70 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::{
+    matchmaking::{queue_position, rank_servers, MatchmakingConfig},
+    poller::StatusCache,
+    Client,
+};
+
+/// Replace every {placeholder} in a template, leaving unknown ones alone
+fn fill(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(key, _)| *key == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Build the message of the day printed to a client's console as it connects
+pub fn motd(
+    config: &MatchmakingConfig,
+    status: &StatusCache,
+    clients: &HashMap<SocketAddr, Client>,
+    addr: SocketAddr,
+) -> String {
+    let (position, queue) = queue_position(clients, addr);
+    let best = match rank_servers(config, status).first() {
+        Some(c) => format!("{} ({})", c.name, c.addr),
+        None => "None available".to_string(),
+    };
+    let mut servers: Vec<&str> = config
+        .servers()
+        .iter()
+        .map(|(key, server)| server.name().unwrap_or(key))
+        .collect();
+    servers.sort_unstable();
+    let servers: Vec<String> = servers.iter().map(|name| format!("  {}", name)).collect();
+    let mut motd = fill(
+        &config.motd(),
+        &[
+            ("hostname", config.hostname()),
+            ("position", position.to_string()),
+            ("queue", queue.to_string()),
+            ("best", best),
+            ("servers", servers.join("\n")),
+        ],
+    );
+    if !motd.ends_with('\n') {
+        motd.push('\n');
+    }
+    motd
+}
@@ -0,0 +1,250 @@
+use a2s::info::{ExtendedServerInfo, Info, ServerOS, ServerType, SourceTVInfo, TheShip};
+use bitbuffer::{BitReadBuffer, BitReadStream, LittleEndian};
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{self, UdpSocket},
+    task, time,
+};
+
+use crate::matchmaking::{MatchmakingConfig, Server, SharedConfig};
+
+/// How long a server gets to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most recent A2S_INFO response received from a server
+#[derive(Clone)]
+pub struct ServerStatus {
+    pub addr: SocketAddr,
+    pub info: Info,
+    pub updated: SystemTime,
+}
+
+impl ServerStatus {
+    /// Returns true if the snapshot is younger than the given age
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        match SystemTime::now().duration_since(self.updated) {
+            Ok(age) => age <= max_age,
+            Err(_) => true,
+        }
+    }
+}
+
+/// Latest known status of every pooled server, keyed by the server's config name
+pub type StatusCache = Arc<RwLock<HashMap<String, ServerStatus>>>;
+
+#[derive(Debug)]
+enum QueryError {
+    InvalidResponse,
+    Timeout,
+    Unresolved,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for QueryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Parse the body of an A2S_INFO response
+fn parse_info(data: &[u8]) -> Result<Info, Box<dyn Error + Send + Sync>> {
+    let buffer = BitReadBuffer::new(data, LittleEndian);
+    let mut reader = BitReadStream::new(buffer);
+    if reader.read_int::<u8>(8)? != 0x49 {
+        return Err(Box::new(QueryError::InvalidResponse));
+    }
+    let protocol = reader.read_int(8)?;
+    let name = reader.read_string(None)?.to_string();
+    let map = reader.read_string(None)?.to_string();
+    let folder = reader.read_string(None)?.to_string();
+    let game = reader.read_string(None)?.to_string();
+    let app_id = reader.read_int(16)?;
+    let players = reader.read_int(8)?;
+    let max_players = reader.read_int(8)?;
+    let bots = reader.read_int(8)?;
+    let server_type = match reader.read_int::<u8>(8)? {
+        b'd' => ServerType::Dedicated,
+        b'p' => ServerType::SourceTV,
+        _ => ServerType::NonDedicated,
+    };
+    let server_os = match reader.read_int::<u8>(8)? {
+        b'l' => ServerOS::Linux,
+        b'w' => ServerOS::Windows,
+        _ => ServerOS::Mac,
+    };
+    let visibility = reader.read_int::<u8>(8)? != 0;
+    let vac = reader.read_int::<u8>(8)? != 0;
+    // The Ship has a few extra fields
+    let the_ship = if app_id == 2400 {
+        Some(TheShip {
+            mode: reader.read_int::<u8>(8)?.into(),
+            witnesses: reader.read_int(8)?,
+            duration: reader.read_int(8)?,
+        })
+    } else {
+        None
+    };
+    let version = reader.read_string(None)?.to_string();
+    let edf = if reader.bits_left() >= 8 {
+        reader.read_int(8)?
+    } else {
+        0
+    };
+    let mut extended_server_info = ExtendedServerInfo {
+        port: None,
+        steam_id: None,
+        keywords: None,
+        game_id: None,
+    };
+    if edf & 0x80 != 0 {
+        extended_server_info.port = Some(reader.read_int(16)?);
+    }
+    if edf & 0x10 != 0 {
+        extended_server_info.steam_id = Some(reader.read_int(64)?);
+    }
+    let source_tv = if edf & 0x40 != 0 {
+        Some(SourceTVInfo {
+            port: reader.read_int(16)?,
+            name: reader.read_string(None)?.to_string(),
+        })
+    } else {
+        None
+    };
+    if edf & 0x20 != 0 {
+        extended_server_info.keywords = Some(reader.read_string(None)?.to_string());
+    }
+    if edf & 0x01 != 0 {
+        extended_server_info.game_id = Some(reader.read_int(64)?);
+    }
+    Ok(Info {
+        protocol,
+        name,
+        map,
+        folder,
+        game,
+        app_id,
+        players,
+        max_players,
+        bots,
+        server_type,
+        server_os,
+        visibility,
+        vac,
+        the_ship,
+        version,
+        edf,
+        extended_server_info,
+        source_tv,
+    })
+}
+
+/// Query a single server's info
+async fn query(addr: SocketAddr) -> Result<Info, Box<dyn Error + Send + Sync>> {
+    let sock = if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0").await?
+    } else {
+        UdpSocket::bind("[::]:0").await?
+    };
+    // Only accept replies from the server we are asking
+    sock.connect(addr).await?;
+    let mut request = Vec::new();
+    request.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+    request.push(0x54); // Type
+    request.extend_from_slice("Source Engine Query\0".as_bytes());
+    let mut buffer = vec![0; 1400];
+    // Servers may ask us to repeat the request with a challenge first
+    for _ in 0..2 {
+        sock.send(&request).await?;
+        let len = match time::timeout(QUERY_TIMEOUT, sock.recv(&mut buffer)).await {
+            Ok(len) => len?,
+            Err(_) => return Err(Box::new(QueryError::Timeout)),
+        };
+        if len < 5 || buffer[0..4] != [0xFF, 0xFF, 0xFF, 0xFF] {
+            return Err(Box::new(QueryError::InvalidResponse));
+        }
+        if buffer[4] == 0x41 && len >= 9 {
+            let challenge = u32::from_le_bytes(buffer[5..9].try_into().unwrap());
+            request.truncate(25);
+            request.extend_from_slice(&challenge.to_le_bytes());
+            continue;
+        }
+        return parse_info(&buffer[4..len]);
+    }
+    Err(Box::new(QueryError::InvalidResponse))
+}
+
+/// Look up a server's current address and query it
+async fn resolve_and_query(
+    server: Server,
+    bind_addr: SocketAddr,
+) -> Result<ServerStatus, Box<dyn Error + Send + Sync>> {
+    // Hostnames are resolved on every poll so servers can move between addresses
+    let addrs: Vec<SocketAddr> = net::lookup_host(server.address()).await?.collect();
+    // Resolvers tend to put AAAA records first, but TF2 clients can only connect over IPv4.
+    // Listening on :: takes IPv4 clients as well, so only a specific IPv6 listener prefers IPv6
+    let ipv4 = bind_addr.is_ipv4() || bind_addr.ip().is_unspecified();
+    let addr = match addrs.iter().find(|a| a.is_ipv4() == ipv4).or(addrs.first()) {
+        Some(addr) => *addr,
+        None => return Err(Box::new(QueryError::Unresolved)),
+    };
+    let info = query(addr).await?;
+    Ok(ServerStatus {
+        addr,
+        info,
+        updated: SystemTime::now(),
+    })
+}
+
+/// Query every configured server concurrently and update the cache with the results
+async fn poll(config: &MatchmakingConfig, cache: &StatusCache) {
+    let mut queries = Vec::new();
+    for (key, server) in config.servers() {
+        let query = resolve_and_query(server.clone(), config.bind_addr());
+        queries.push((key.clone(), task::spawn(query)));
+    }
+    for (key, handle) in queries {
+        match handle.await {
+            Ok(Ok(status)) => {
+                cache.write().unwrap().insert(key, status);
+            }
+            // Keep the last snapshot, a single lost datagram shouldn't take a server out of
+            // the pool or release its reservations, rank_servers retires it once it gets old
+            Ok(Err(e)) => debug!("Failed to query {}: {}", key, e),
+            Err(e) => error!("Query task for {} failed: {}", key, e),
+        }
+    }
+    // Forget about servers that are no longer in the pool
+    cache
+        .write()
+        .unwrap()
+        .retain(|key, _| config.servers().contains_key(key));
+}
+
+/// Start polling the configured servers in the background
+pub fn spawn_poller(config: SharedConfig) -> StatusCache {
+    let cache = StatusCache::default();
+    let poller_cache = cache.clone();
+    task::spawn(async move {
+        loop {
+            // Pick up any changes to the pool since the last poll
+            let current = config.read().unwrap().clone();
+            poll(&current, &poller_cache).await;
+            time::sleep(current.poll_interval()).await;
+        }
+    });
+    cache
+}
@@ -0,0 +1,43 @@
+use log::{error, info, warn};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{task, time};
+
+use crate::matchmaking::{MatchmakingConfig, SharedConfig};
+
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns when the file was last modified
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Watch the config file and swap in new versions as they are saved
+pub fn spawn_watcher(path: PathBuf, config: SharedConfig) {
+    task::spawn(async move {
+        let mut last = modified(&path);
+        loop {
+            time::sleep(WATCH_INTERVAL).await;
+            let current = modified(&path);
+            if current == last {
+                continue;
+            }
+            last = current;
+            match MatchmakingConfig::load(&path) {
+                Ok(new) => {
+                    if new.bind_addr() != config.read().unwrap().bind_addr() {
+                        warn!("Changing the bind address requires a restart");
+                    }
+                    *config.write().unwrap() = Arc::new(new);
+                    info!("Reloaded {}", path.display());
+                }
+                Err(e) => error!("Rejected changes to {}: {}", path.display(), e),
+            }
+        }
+    });
+}
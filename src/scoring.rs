@@ -0,0 +1,108 @@
+use a2s::info::Info;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Weighted rules used to score a server from its A2S_INFO response
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ScoringRules {
+    /// Player count a server needs before it is rewarded for its players
+    pub min_players: u8,
+    /// Points awarded per player once min_players is reached
+    pub player_weight: f64,
+    /// The max player count servers are expected to have
+    pub target_max_players: u8,
+    /// Points removed per slot of difference from target_max_players
+    pub max_players_weight: f64,
+    /// Points removed per bot
+    pub bot_penalty: f64,
+    /// Points awarded for a completely full server, scaled by how full it is
+    pub fill_weight: f64,
+    /// Bonus points per server region
+    pub regions: HashMap<String, f64>,
+    /// Bonus points per map, matched against the start of the map name
+    pub maps: HashMap<String, f64>,
+}
+
+impl Default for ScoringRules {
+    fn default() -> Self {
+        Self {
+            min_players: 6,
+            player_weight: 1.0,
+            target_max_players: 24,
+            max_players_weight: 1.0,
+            bot_penalty: 1.0,
+            fill_weight: 0.0,
+            regions: HashMap::new(),
+            maps: HashMap::new(),
+        }
+    }
+}
+
+impl ScoringRules {
+    /// Score a server's info against the rules
+    pub fn evaluate(&self, info: &Info, region: Option<&str>) -> f64 {
+        let mut score = 0.0;
+        // Reward servers for having players
+        if info.players >= self.min_players {
+            score += info.players as f64 * self.player_weight;
+        }
+        // Punish servers for straying from the target maxplayer limit
+        score -= (info.max_players as f64 - self.target_max_players as f64).abs()
+            * self.max_players_weight;
+        score -= info.bots as f64 * self.bot_penalty;
+        if info.max_players > 0 {
+            score += info.players as f64 / info.max_players as f64 * self.fill_weight;
+        }
+        if let Some(bonus) = region.and_then(|r| self.regions.get(r)) {
+            score += bonus;
+        }
+        score += self
+            .maps
+            .iter()
+            .filter(|(map, _)| info.map.starts_with(map.as_str()))
+            .map(|(_, bonus)| bonus)
+            .sum::<f64>();
+        score
+    }
+
+    /// Returns a copy of the rules with a server's overrides applied
+    pub fn with_override(&self, o: &ScoringOverride) -> Self {
+        let mut rules = self.clone();
+        if let Some(v) = o.min_players {
+            rules.min_players = v;
+        }
+        if let Some(v) = o.player_weight {
+            rules.player_weight = v;
+        }
+        if let Some(v) = o.target_max_players {
+            rules.target_max_players = v;
+        }
+        if let Some(v) = o.max_players_weight {
+            rules.max_players_weight = v;
+        }
+        if let Some(v) = o.bot_penalty {
+            rules.bot_penalty = v;
+        }
+        if let Some(v) = o.fill_weight {
+            rules.fill_weight = v;
+        }
+        rules.regions.extend(o.regions.clone());
+        rules.maps.extend(o.maps.clone());
+        rules
+    }
+}
+
+/// Per-server replacements for the global scoring rules
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ScoringOverride {
+    pub min_players: Option<u8>,
+    pub player_weight: Option<f64>,
+    pub target_max_players: Option<u8>,
+    pub max_players_weight: Option<f64>,
+    pub bot_penalty: Option<f64>,
+    pub fill_weight: Option<f64>,
+    pub regions: HashMap<String, f64>,
+    pub maps: HashMap<String, f64>,
+}
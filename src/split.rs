@@ -0,0 +1,315 @@
+use bzip2::{Decompress, Status};
+use crc::crc32;
+use log::warn;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime},
+};
+
+/// Largest packet that is sent without being split
+pub const MAX_PACKET_SIZE: usize = 1400;
+/// Payload carried by each part of a split packet
+const SPLIT_SIZE: usize = 1248;
+/// Most parts a split packet may have
+const MAX_PARTS: usize = 32;
+/// Largest size a compressed split packet may decompress to
+const MAX_DECOMPRESSED_SIZE: usize = MAX_PARTS * SPLIT_SIZE * 4;
+/// How long we wait for the rest of a split packet
+const SPLIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Set in the packet ID when the payload is bzip2 compressed
+const COMPRESSED: u32 = 0x80000000;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Debug)]
+enum SplitError {
+    BadChecksum,
+    BadSize,
+    Malformed,
+    TooManyParts,
+}
+
+impl Display for SplitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SplitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Split a packet into parts using the 0xFFFFFFFE header if it is too large to send at once
+pub fn split_packet(packet: Vec<u8>) -> Vec<Vec<u8>> {
+    if packet.len() <= MAX_PACKET_SIZE {
+        return vec![packet];
+    }
+    // IDs never use the top bit since it marks compressed packets
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) & !COMPRESSED;
+    let chunks: Vec<&[u8]> = packet.chunks(SPLIT_SIZE).collect();
+    let total = chunks.len();
+    if total > MAX_PARTS {
+        warn!(
+            "Dropping a {} byte packet that is too large to split",
+            packet.len()
+        );
+        return vec![];
+    }
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(number, chunk)| {
+            let mut part = Vec::with_capacity(12 + chunk.len());
+            part.extend_from_slice(&[0xFE, 0xFF, 0xFF, 0xFF]); // Header
+            part.extend_from_slice(&id.to_le_bytes()); // Packet ID
+            part.push(total as u8); // Total number of parts
+            part.push(number as u8); // Number of this part
+            part.extend_from_slice(&(SPLIT_SIZE as u16).to_le_bytes()); // Split size
+            part.extend_from_slice(chunk);
+            part
+        })
+        .collect()
+}
+
+/// The parts of a split packet received so far
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    started: SystemTime,
+}
+
+/// Reassembles split packets received from clients
+#[derive(Default)]
+pub struct SplitPackets {
+    pending: HashMap<(SocketAddr, u32), Partial>,
+}
+
+impl SplitPackets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a part to its packet, returning the whole packet once every part has arrived
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if data.len() < 12 {
+            return Err(Box::new(SplitError::Malformed));
+        }
+        let id = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let total = data[8] as usize;
+        let number = data[9] as usize;
+        if total == 0 || total > MAX_PARTS {
+            return Err(Box::new(SplitError::TooManyParts));
+        }
+        if number >= total {
+            return Err(Box::new(SplitError::Malformed));
+        }
+
+        // Forget about packets whose parts never all arrived
+        let now = SystemTime::now();
+        self.pending
+            .retain(|_, p| now.duration_since(p.started).unwrap_or_default() < SPLIT_TIMEOUT);
+
+        let partial = self.pending.entry((addr, id)).or_insert_with(|| Partial {
+            parts: vec![None; total],
+            started: now,
+        });
+        if partial.parts.len() != total {
+            self.pending.remove(&(addr, id));
+            return Err(Box::new(SplitError::Malformed));
+        }
+        partial.parts[number] = Some(data[12..].to_vec());
+        if partial.parts.iter().any(|p| p.is_none()) {
+            return Ok(None);
+        }
+
+        let partial = self.pending.remove(&(addr, id)).unwrap();
+        let packet: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        if id & COMPRESSED != 0 {
+            decompress(&packet).map(Some)
+        } else {
+            Ok(Some(packet))
+        }
+    }
+}
+
+/// Decompress a reassembled bzip2 packet, checking its size and checksum
+fn decompress(packet: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if packet.len() < 8 {
+        return Err(Box::new(SplitError::Malformed));
+    }
+    let size = u32::from_le_bytes(packet[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(packet[4..8].try_into().unwrap());
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(Box::new(SplitError::BadSize));
+    }
+    // With room for exactly the announced size, anything but the end of the stream means the
+    // data was cut short or is larger than announced
+    let mut decompressed = Vec::with_capacity(size);
+    if Decompress::new(false).decompress_vec(&packet[8..], &mut decompressed)? != Status::StreamEnd
+        || decompressed.len() != size
+    {
+        return Err(Box::new(SplitError::BadSize));
+    }
+    if crc32::checksum_ieee(&decompressed) != checksum {
+        return Err(Box::new(SplitError::BadChecksum));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use bzip2::{write::BzEncoder, Compression};
+    use crc::crc32;
+    use std::{io::Write, net::SocketAddr};
+
+    use super::{split_packet, SplitPackets, COMPRESSED, MAX_DECOMPRESSED_SIZE, SPLIT_SIZE};
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:27005".parse().unwrap()
+    }
+
+    /// Split a payload into parts the way clients do
+    fn parts(id: u32, payload: &[u8]) -> Vec<Vec<u8>> {
+        let chunks: Vec<&[u8]> = payload.chunks(SPLIT_SIZE).collect();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(number, chunk)| {
+                let mut part = vec![0xFE, 0xFF, 0xFF, 0xFF];
+                part.extend_from_slice(&id.to_le_bytes());
+                part.push(chunks.len() as u8);
+                part.push(number as u8);
+                part.extend_from_slice(&(SPLIT_SIZE as u16).to_le_bytes());
+                part.extend_from_slice(chunk);
+                part
+            })
+            .collect()
+    }
+
+    /// A compressed payload, preceded by the size and checksum of the data
+    fn compressed(size: u32, checksum: u32, data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(vec![], Compression::Default);
+        encoder.write_all(data).unwrap();
+        let mut payload = size.to_le_bytes().to_vec();
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        payload.extend(encoder.finish().unwrap());
+        payload
+    }
+
+    /// Feed every part in, returning what the last one produced
+    fn reassemble(parts: Vec<Vec<u8>>) -> Result<Option<Vec<u8>>, String> {
+        let mut splits = SplitPackets::new();
+        let mut result = Ok(None);
+        for part in parts {
+            result = splits.insert(addr(), &part).map_err(|e| e.to_string());
+        }
+        result
+    }
+
+    fn data() -> Vec<u8> {
+        (0..5000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let data = data();
+        let parts = split_packet(data.clone());
+        assert_eq!(parts.len(), 5);
+        assert_eq!(reassemble(parts).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn parts_out_of_order() {
+        let data = data();
+        let mut parts = parts(1, &data);
+        parts.reverse();
+        assert_eq!(reassemble(parts).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn bzip2() {
+        let data = data();
+        let payload = compressed(data.len() as u32, crc32::checksum_ieee(&data), &data);
+        assert_eq!(
+            reassemble(parts(1 | COMPRESSED, &payload)).unwrap(),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn bzip2_bad_checksum() {
+        let data = data();
+        let payload = compressed(data.len() as u32, 0, &data);
+        assert!(reassemble(parts(1 | COMPRESSED, &payload)).is_err());
+    }
+
+    #[test]
+    fn bzip2_wrong_size() {
+        let data = data();
+        let checksum = crc32::checksum_ieee(&data);
+        for size in [data.len() - 1, data.len() + 1] {
+            let payload = compressed(size as u32, checksum, &data);
+            assert!(reassemble(parts(1 | COMPRESSED, &payload)).is_err());
+        }
+    }
+
+    #[test]
+    fn bzip2_oversized() {
+        // Claims more than we allow, even though the data itself is small
+        let data = vec![0; 100];
+        let size = MAX_DECOMPRESSED_SIZE as u32 + 1;
+        let payload = compressed(size, crc32::checksum_ieee(&data), &data);
+        assert!(reassemble(parts(1 | COMPRESSED, &payload)).is_err());
+        // Decompresses to more than we allow
+        let data = vec![0; MAX_DECOMPRESSED_SIZE + 1];
+        let payload = compressed(100, crc32::checksum_ieee(&data), &data);
+        assert!(reassemble(parts(1 | COMPRESSED, &payload)).is_err());
+    }
+
+    #[test]
+    fn bzip2_truncated() {
+        let data = data();
+        let payload = compressed(data.len() as u32, crc32::checksum_ieee(&data), &data);
+        // An empty payload has no parts to send at all
+        for end in 1..payload.len() {
+            assert!(
+                reassemble(parts(1 | COMPRESSED, &payload[..end])).is_err(),
+                "{} bytes",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let mut splits = SplitPackets::new();
+        // Too short to have a header
+        assert!(splits.insert(addr(), &[0xFE, 0xFF, 0xFF, 0xFF, 1]).is_err());
+        let mut part = parts(1, &data()).remove(0);
+        // No parts at all, and more than we allow
+        for total in [0, 33] {
+            part[8] = total;
+            assert!(splits.insert(addr(), &part).is_err());
+        }
+        // A part past the end
+        part[8] = 2;
+        part[9] = 2;
+        assert!(splits.insert(addr(), &part).is_err());
+        // Parts of the same packet that disagree on how many parts there are
+        part[9] = 0;
+        assert_eq!(splits.insert(addr(), &part).unwrap(), None);
+        part[8] = 3;
+        part[9] = 1;
+        assert!(splits.insert(addr(), &part).is_err());
+    }
+}
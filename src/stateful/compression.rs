@@ -0,0 +1,207 @@
+use snap::raw::{decompress_len, Decoder};
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+/// Largest uncompressed block we are willing to decode
+pub const MAX_PAYLOAD: usize = 96000;
+
+#[derive(Debug)]
+enum CompressionError {
+    BadSize,
+    Malformed,
+    UnknownFormat,
+}
+
+impl Display for CompressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CompressionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Decompress a block the client compressed, checking it against the size it announced
+pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if size > MAX_PAYLOAD {
+        return Err(Box::new(CompressionError::BadSize));
+    }
+    if data.len() < 4 {
+        return Err(Box::new(CompressionError::Malformed));
+    }
+    let output = match &data[0..4] {
+        b"LZSS" => lzss(&data[4..], size)?,
+        b"SNAP" => {
+            // Check the length snappy will produce before allocating for it
+            if decompress_len(&data[4..])? != size {
+                return Err(Box::new(CompressionError::BadSize));
+            }
+            Decoder::new().decompress_vec(&data[4..])?
+        }
+        _ => return Err(Box::new(CompressionError::UnknownFormat)),
+    };
+    if output.len() != size {
+        return Err(Box::new(CompressionError::BadSize));
+    }
+    Ok(output)
+}
+
+/// Decode Valve's LZSS, which follows the magic with the uncompressed size
+fn lzss(data: &[u8], size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if data.len() < 4 {
+        return Err(Box::new(CompressionError::Malformed));
+    }
+    if u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize != size {
+        return Err(Box::new(CompressionError::BadSize));
+    }
+    let mut input = data[4..].iter().copied();
+    let mut next = || input.next().ok_or(CompressionError::Malformed);
+    let mut output = Vec::with_capacity(size);
+    let mut commands = 0u8;
+    let mut i = 0;
+    loop {
+        // Every byte of commands covers the next eight items
+        if i % 8 == 0 {
+            commands = next()?;
+        }
+        i += 1;
+        if commands & 0x01 != 0 {
+            let high = next()? as usize;
+            let low = next()? as usize;
+            let position = (high << 4) | (low >> 4);
+            let count = (low & 0x0F) + 1;
+            // A run of one marks the end of the data
+            if count == 1 {
+                break;
+            }
+            if position >= output.len() || output.len() + count > size {
+                return Err(Box::new(CompressionError::Malformed));
+            }
+            // Runs may overlap the bytes they produce
+            let start = output.len() - position - 1;
+            for j in 0..count {
+                output.push(output[start + j]);
+            }
+        } else {
+            if output.len() >= size {
+                return Err(Box::new(CompressionError::Malformed));
+            }
+            output.push(next()?);
+        }
+        commands >>= 1;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use snap::raw::Encoder;
+
+    use super::{decompress, MAX_PAYLOAD};
+
+    /// Prefix LZSS commands with the magic and the size they decode to
+    fn lzss_block(size: u32, commands: &[u8]) -> Vec<u8> {
+        [b"LZSS" as &[u8], &size.to_le_bytes(), commands].concat()
+    }
+
+    /// "abc" as literals, then a run of 6 that overlaps the bytes it produces
+    fn repeating() -> Vec<u8> {
+        lzss_block(9, &[0x18, b'a', b'b', b'c', 0x00, 0x25, 0x00, 0x00])
+    }
+
+    fn snap_block(data: &[u8]) -> Vec<u8> {
+        [
+            b"SNAP" as &[u8],
+            &Encoder::new().compress_vec(data).unwrap(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn lzss_overlapping_run() {
+        assert_eq!(decompress(&repeating(), 9).unwrap(), b"abcabcabc");
+    }
+
+    #[test]
+    fn lzss_literals_across_command_bytes() {
+        let mut commands = vec![0x00];
+        commands.extend_from_slice(b"abcdefgh");
+        commands.push(0x04); // Two literals then the end
+        commands.extend_from_slice(b"ij");
+        commands.extend_from_slice(&[0x00, 0x00]);
+        let block = lzss_block(10, &commands);
+        assert_eq!(decompress(&block, 10).unwrap(), b"abcdefghij");
+    }
+
+    #[test]
+    fn lzss_truncated() {
+        let block = repeating();
+        for end in 0..block.len() {
+            assert!(decompress(&block[..end], 9).is_err(), "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn lzss_wrong_size() {
+        // The header and the caller disagree
+        assert!(decompress(&repeating(), 8).is_err());
+        // Both claim less than the data produces
+        let block = lzss_block(8, &[0x18, b'a', b'b', b'c', 0x00, 0x25, 0x00, 0x00]);
+        assert!(decompress(&block, 8).is_err());
+        // Both claim more than the data produces
+        let block = lzss_block(10, &[0x18, b'a', b'b', b'c', 0x00, 0x25, 0x00, 0x00]);
+        assert!(decompress(&block, 10).is_err());
+    }
+
+    #[test]
+    fn lzss_run_before_start() {
+        // A run reaching further back than the output goes
+        let block = lzss_block(9, &[0x02, b'a', 0x00, 0x55, 0x00, 0x00]);
+        assert!(decompress(&block, 9).is_err());
+    }
+
+    #[test]
+    fn snappy() {
+        let data = b"hello hello hello hello".repeat(10);
+        assert_eq!(decompress(&snap_block(&data), data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn snappy_wrong_size() {
+        let data = b"hello hello hello hello".repeat(10);
+        assert!(decompress(&snap_block(&data), data.len() - 1).is_err());
+        assert!(decompress(&snap_block(&data), data.len() + 1).is_err());
+    }
+
+    #[test]
+    fn snappy_truncated() {
+        let data = b"hello hello hello hello".repeat(10);
+        let block = snap_block(&data);
+        for end in 0..block.len() {
+            assert!(
+                decompress(&block[..end], data.len()).is_err(),
+                "{} bytes",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn oversized() {
+        let data = vec![0; MAX_PAYLOAD + 1];
+        assert!(decompress(&snap_block(&data), data.len()).is_err());
+        let block = lzss_block(MAX_PAYLOAD as u32 + 1, &[0x01, 0x00, 0x00]);
+        assert!(decompress(&block, MAX_PAYLOAD + 1).is_err());
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(decompress(b"ZLIB\x00\x00\x00\x00", 0).is_err());
+    }
+}
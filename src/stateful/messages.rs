@@ -1,83 +1,925 @@
 use bitbuffer::{BitReadStream, LittleEndian};
 use log::error;
+use std::collections::HashMap;
 use std::error::Error;
-use std::{collections::HashMap, mem};
 
-#[derive(Debug)]
-#[allow(non_camel_case_types)]
+use super::util::{
+    read_bit_data, read_varint, write_bit_data, write_varint, BitWriter, WriteError,
+};
+
+/// Bits used to send the type of each message
+const TYPE_BITS: usize = 6;
+
+/// Path ids that file checks can refer to by number
+const COMMON_PATH_IDS: [&str; 2] = ["GAME", "MOD"];
+/// Directories that file checks can refer to by number
+const COMMON_PREFIXES: [&str; 4] = ["materials", "models", "sounds", "scripts"];
+
+/// Message types, net messages can be sent both ways
+mod ids {
+    pub const NET_NOP: u8 = 0;
+    pub const NET_DISCONNECT: u8 = 1;
+    pub const NET_FILE: u8 = 2;
+    pub const NET_TICK: u8 = 3;
+    pub const NET_STRING_CMD: u8 = 4;
+    pub const NET_SET_CONVARS: u8 = 5;
+    pub const NET_SIGNON_STATE: u8 = 6;
+
+    pub const SVC_PRINT: u8 = 7;
+    pub const SVC_SERVER_INFO: u8 = 8;
+    pub const SVC_CREATE_STRING_TABLE: u8 = 12;
+    pub const SVC_SET_VIEW: u8 = 18;
+    pub const SVC_USER_MESSAGE: u8 = 23;
+
+    pub const CLC_CLIENT_INFO: u8 = 8;
+    pub const CLC_MOVE: u8 = 9;
+    pub const CLC_VOICE_DATA: u8 = 10;
+    pub const CLC_BASELINE_ACK: u8 = 11;
+    pub const CLC_LISTEN_EVENTS: u8 = 12;
+    pub const CLC_RESPOND_CVAR_VALUE: u8 = 13;
+    pub const CLC_FILE_CRC_CHECK: u8 = 14;
+    pub const CLC_SAVE_REPLAY: u8 = 15;
+    pub const CLC_CMD_KEY_VALUES: u8 = 16;
+    pub const CLC_FILE_MD5_CHECK: u8 = 17;
+}
+
+/// Which side sent a message, since server and client messages reuse the same types
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(test), allow(dead_code))] // We only decode server messages in tests
+pub enum Sender {
+    Client,
+    Server,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[allow(dead_code, non_camel_case_types)]
 pub enum Messages {
     NET_NOP,
-    NET_DISCONNECT { reason: String },
-    NET_SET_CONVARS { convars: HashMap<String, String> },
-    NET_SIGNON_STATE { state: u8, spawn_count: i32 },
-    SVC_PRINT { message: String },
-    /*SVC_SERVER_INFO {
+    NET_DISCONNECT {
+        reason: String,
+    },
+    NET_FILE {
+        transfer_id: u32,
+        filename: String,
+        requested: bool,
+    },
+    NET_TICK {
+        tick: i32,
+        host_frametime: u16,
+        host_frametime_std_dev: u16,
+    },
+    NET_STRING_CMD {
+        command: String,
+    },
+    NET_SET_CONVARS {
+        convars: HashMap<String, String>,
+    },
+    NET_SIGNON_STATE {
+        state: u8,
+        spawn_count: i32,
+    },
+    SVC_PRINT {
+        message: String,
+    },
+    SVC_SERVER_INFO {
         protocol: u16,
         server_count: u32,
         hltv: bool,
         dedicated: bool,
+        client_crc: u32,
         max_classes: u16,
         md5_map: [u8; 16],
         player_slot: u8,
         max_clients: u8,
-        tick_interval: u32,
+        tick_interval: f32,
         os: char,
         game_dir: String,
         map_name: String,
         sky_name: String,
         host_name: String,
         replay: bool,
-    },*/
-    SVC_STRING_CMD { command: String },
+    },
+    SVC_CREATE_STRING_TABLE {
+        name: String,
+        max_entries: u16,
+        num_entries: u16,
+        user_data: Option<(u16, u8)>, // Size in bytes and bits of fixed size user data
+        length: u32,                  // In bits
+        data: Vec<u8>,
+        compressed: bool,
+    },
+    SVC_SET_VIEW {
+        entity: u16,
+    },
+    SVC_USER_MESSAGE {
+        msg_type: u8,
+        length: u16, // In bits
+        data: Vec<u8>,
+    },
+    CLC_CLIENT_INFO {
+        server_count: i32,
+        send_table_crc: u32,
+        hltv: bool,
+        replay: bool,
+        friends_id: u32,
+        friends_name: String,
+        custom_files: [Option<u32>; 4],
+    },
+    CLC_MOVE {
+        new_commands: u8,
+        backup_commands: u8,
+        length: u16, // In bits
+        data: Vec<u8>,
+    },
+    CLC_VOICE_DATA {
+        length: u16, // In bits
+        data: Vec<u8>,
+    },
+    CLC_BASELINE_ACK {
+        baseline_tick: i32,
+        baseline_nr: u8,
+    },
+    CLC_LISTEN_EVENTS {
+        events: [u32; 16], // One bit per game event
+    },
+    CLC_RESPOND_CVAR_VALUE {
+        cookie: i32,
+        status: i8,
+        name: String,
+        value: String,
+    },
+    CLC_FILE_CRC_CHECK {
+        path_id: String,
+        filename: String,
+        md5: [u8; 16],
+        hash_type: u32,
+        file_len: u32,
+        pack_file_number: u32,
+        pack_file_id: u32,
+        file_fraction: u32,
+    },
+    CLC_SAVE_REPLAY {
+        filename: String,
+        start_send_byte: u8,
+        post_death_record_time: f32,
+    },
+    CLC_CMD_KEY_VALUES {
+        data: Vec<u8>,
+    },
+    CLC_FILE_MD5_CHECK {
+        path_id: String,
+        filename: String,
+        md5: [u8; 16],
+    },
 }
 
-pub fn process_messages(
-    reader: &mut BitReadStream<LittleEndian>,
-) -> Result<Vec<Messages>, Box<dyn Error>> {
-    let mut messages = vec![];
+impl Messages {
+    /// The type the message is sent as
+    pub fn id(&self) -> u8 {
+        match self {
+            Messages::NET_NOP => ids::NET_NOP,
+            Messages::NET_DISCONNECT { .. } => ids::NET_DISCONNECT,
+            Messages::NET_FILE { .. } => ids::NET_FILE,
+            Messages::NET_TICK { .. } => ids::NET_TICK,
+            Messages::NET_STRING_CMD { .. } => ids::NET_STRING_CMD,
+            Messages::NET_SET_CONVARS { .. } => ids::NET_SET_CONVARS,
+            Messages::NET_SIGNON_STATE { .. } => ids::NET_SIGNON_STATE,
+            Messages::SVC_PRINT { .. } => ids::SVC_PRINT,
+            Messages::SVC_SERVER_INFO { .. } => ids::SVC_SERVER_INFO,
+            Messages::SVC_CREATE_STRING_TABLE { .. } => ids::SVC_CREATE_STRING_TABLE,
+            Messages::SVC_SET_VIEW { .. } => ids::SVC_SET_VIEW,
+            Messages::SVC_USER_MESSAGE { .. } => ids::SVC_USER_MESSAGE,
+            Messages::CLC_CLIENT_INFO { .. } => ids::CLC_CLIENT_INFO,
+            Messages::CLC_MOVE { .. } => ids::CLC_MOVE,
+            Messages::CLC_VOICE_DATA { .. } => ids::CLC_VOICE_DATA,
+            Messages::CLC_BASELINE_ACK { .. } => ids::CLC_BASELINE_ACK,
+            Messages::CLC_LISTEN_EVENTS { .. } => ids::CLC_LISTEN_EVENTS,
+            Messages::CLC_RESPOND_CVAR_VALUE { .. } => ids::CLC_RESPOND_CVAR_VALUE,
+            Messages::CLC_FILE_CRC_CHECK { .. } => ids::CLC_FILE_CRC_CHECK,
+            Messages::CLC_SAVE_REPLAY { .. } => ids::CLC_SAVE_REPLAY,
+            Messages::CLC_CMD_KEY_VALUES { .. } => ids::CLC_CMD_KEY_VALUES,
+            Messages::CLC_FILE_MD5_CHECK { .. } => ids::CLC_FILE_MD5_CHECK,
+        }
+    }
 
-    loop {
-        if reader.bits_left() < 6 {
-            break;
+    /// Serialize the message, including its type, failing if a length doesn't match its data
+    pub fn write(&self, writer: &mut BitWriter) -> Result<(), Box<dyn Error>> {
+        writer.write_int(self.id(), TYPE_BITS);
+        match self {
+            Messages::NET_NOP => {}
+            Messages::NET_DISCONNECT { reason } => writer.write_string(reason),
+            Messages::NET_FILE {
+                transfer_id,
+                filename,
+                requested,
+            } => {
+                writer.write_int(*transfer_id, 32);
+                writer.write_string(filename);
+                writer.write_bool(*requested);
+            }
+            Messages::NET_TICK {
+                tick,
+                host_frametime,
+                host_frametime_std_dev,
+            } => {
+                writer.write_int(*tick as u32, 32);
+                writer.write_int(*host_frametime, 16);
+                writer.write_int(*host_frametime_std_dev, 16);
+            }
+            Messages::NET_STRING_CMD { command } => writer.write_string(command),
+            Messages::NET_SET_CONVARS { convars } => {
+                writer.write_int(convars.len() as u8, 8);
+                for (key, value) in convars {
+                    writer.write_string(key);
+                    writer.write_string(value);
+                }
+            }
+            Messages::NET_SIGNON_STATE { state, spawn_count } => {
+                writer.write_int(*state, 8);
+                writer.write_int(*spawn_count as u32, 32);
+            }
+            Messages::SVC_PRINT { message } => writer.write_string(message),
+            Messages::SVC_SERVER_INFO {
+                protocol,
+                server_count,
+                hltv,
+                dedicated,
+                client_crc,
+                max_classes,
+                md5_map,
+                player_slot,
+                max_clients,
+                tick_interval,
+                os,
+                game_dir,
+                map_name,
+                sky_name,
+                host_name,
+                replay,
+            } => {
+                writer.write_int(*protocol, 16);
+                writer.write_int(*server_count, 32);
+                writer.write_bool(*hltv);
+                writer.write_bool(*dedicated);
+                writer.write_int(*client_crc, 32);
+                writer.write_int(*max_classes, 16);
+                writer.write_bytes(md5_map);
+                writer.write_int(*player_slot, 8);
+                writer.write_int(*max_clients, 8);
+                writer.write_float(*tick_interval);
+                writer.write_int(*os as u8, 8);
+                writer.write_string(game_dir);
+                writer.write_string(map_name);
+                writer.write_string(sky_name);
+                writer.write_string(host_name);
+                writer.write_bool(*replay);
+            }
+            Messages::SVC_CREATE_STRING_TABLE {
+                name,
+                max_entries,
+                num_entries,
+                user_data,
+                length,
+                data,
+                compressed,
+            } => {
+                writer.write_string(name);
+                writer.write_int(*max_entries, 16);
+                writer.write_int(*num_entries, entry_bits(*max_entries));
+                write_varint(writer, *length as usize);
+                writer.write_bool(user_data.is_some());
+                if let Some((size, size_bits)) = user_data {
+                    writer.write_int(*size, 12);
+                    writer.write_int(*size_bits, 4);
+                }
+                writer.write_bool(*compressed);
+                write_bit_data(writer, data, *length as usize)?;
+            }
+            Messages::SVC_SET_VIEW { entity } => writer.write_int(*entity, 11),
+            Messages::SVC_USER_MESSAGE {
+                msg_type,
+                length,
+                data,
+            } => {
+                // The length is sent in 11 bits, anything longer would be cut short
+                if *length >= 1 << 11 {
+                    return Err(Box::new(WriteError::LengthTooLong));
+                }
+                writer.write_int(*msg_type, 8);
+                writer.write_int(*length, 11);
+                write_bit_data(writer, data, *length as usize)?;
+            }
+            Messages::CLC_CLIENT_INFO {
+                server_count,
+                send_table_crc,
+                hltv,
+                replay,
+                friends_id,
+                friends_name,
+                custom_files,
+            } => {
+                writer.write_int(*server_count as u32, 32);
+                writer.write_int(*send_table_crc, 32);
+                writer.write_bool(*hltv);
+                writer.write_bool(*replay);
+                writer.write_int(*friends_id, 32);
+                writer.write_string(friends_name);
+                for file in custom_files {
+                    writer.write_bool(file.is_some());
+                    if let Some(crc) = file {
+                        writer.write_int(*crc, 32);
+                    }
+                }
+            }
+            Messages::CLC_MOVE {
+                new_commands,
+                backup_commands,
+                length,
+                data,
+            } => {
+                writer.write_int(*new_commands, 4);
+                writer.write_int(*backup_commands, 3);
+                writer.write_int(*length, 16);
+                write_bit_data(writer, data, *length as usize)?;
+            }
+            Messages::CLC_VOICE_DATA { length, data } => {
+                writer.write_int(*length, 16);
+                write_bit_data(writer, data, *length as usize)?;
+            }
+            Messages::CLC_BASELINE_ACK {
+                baseline_tick,
+                baseline_nr,
+            } => {
+                writer.write_int(*baseline_tick as u32, 32);
+                writer.write_int(*baseline_nr, 1);
+            }
+            Messages::CLC_LISTEN_EVENTS { events } => {
+                for event in events {
+                    writer.write_int(*event, 32);
+                }
+            }
+            Messages::CLC_RESPOND_CVAR_VALUE {
+                cookie,
+                status,
+                name,
+                value,
+            } => {
+                writer.write_int(*cookie as u32, 32);
+                writer.write_int(*status as u8, 4);
+                writer.write_string(name);
+                writer.write_string(value);
+            }
+            Messages::CLC_FILE_CRC_CHECK {
+                path_id,
+                filename,
+                md5,
+                hash_type,
+                file_len,
+                pack_file_number,
+                pack_file_id,
+                file_fraction,
+            } => {
+                writer.write_bool(false); // Reserved
+                write_file_path(writer, path_id, filename);
+                writer.write_bytes(md5);
+                writer.write_int(*hash_type, 32);
+                writer.write_int(*file_len, 32);
+                writer.write_int(*pack_file_number, 32);
+                writer.write_int(*pack_file_id, 32);
+                writer.write_int(*file_fraction, 32);
+            }
+            Messages::CLC_SAVE_REPLAY {
+                filename,
+                start_send_byte,
+                post_death_record_time,
+            } => {
+                writer.write_string(filename);
+                writer.write_int(*start_send_byte, 4);
+                writer.write_float(*post_death_record_time);
+            }
+            Messages::CLC_CMD_KEY_VALUES { data } => {
+                writer.write_int(data.len() as u32, 32);
+                writer.write_bytes(data);
+            }
+            Messages::CLC_FILE_MD5_CHECK {
+                path_id,
+                filename,
+                md5,
+            } => {
+                write_file_path(writer, path_id, filename);
+                writer.write_bytes(md5);
+            }
         }
-        let msg_type: u8 = reader.read_int(6)?;
-        match msg_type {
-            // NET_NOP
-            0 => messages.push(Messages::NET_NOP),
-            // NET_DISCONNECT
-            1 => {
-                let reason = reader.read_string(None)?;
-                messages.push(Messages::NET_DISCONNECT {
-                    reason: reason.to_string(),
-                });
-            }
-            // NET_SET_CONVARS
-            5 => {
-                let num: u8 = reader.read_int(8)?;
+        Ok(())
+    }
 
+    /// Deserialize the body of a message, returns None for types we don't know
+    fn read(
+        id: u8,
+        sender: Sender,
+        reader: &mut BitReadStream<LittleEndian>,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let message = match (sender, id) {
+            (_, ids::NET_NOP) => Messages::NET_NOP,
+            (_, ids::NET_DISCONNECT) => Messages::NET_DISCONNECT {
+                reason: reader.read_string(None)?.to_string(),
+            },
+            (_, ids::NET_FILE) => Messages::NET_FILE {
+                transfer_id: reader.read_int(32)?,
+                filename: reader.read_string(None)?.to_string(),
+                requested: reader.read_bool()?,
+            },
+            (_, ids::NET_TICK) => Messages::NET_TICK {
+                tick: reader.read_int::<u32>(32)? as i32,
+                host_frametime: reader.read_int(16)?,
+                host_frametime_std_dev: reader.read_int(16)?,
+            },
+            (_, ids::NET_STRING_CMD) => Messages::NET_STRING_CMD {
+                command: reader.read_string(None)?.to_string(),
+            },
+            (_, ids::NET_SET_CONVARS) => {
+                let num: u8 = reader.read_int(8)?;
                 let mut convars = HashMap::with_capacity(num.into());
-
                 for _ in 0..num {
                     let key = reader.read_string(None)?;
                     let value = reader.read_string(None)?;
-
                     convars.insert(key.to_string(), value.to_string());
                 }
-                messages.push(Messages::NET_SET_CONVARS { convars });
+                Messages::NET_SET_CONVARS { convars }
             }
-            // NET_SIGNON_STATE
-            6 => {
-                let state: u8 = reader.read_int(8)?;
-                let spawn_count: i32 = unsafe { mem::transmute::<u32, i32>(reader.read_int(32)?) };
-
-                messages.push(Messages::NET_SIGNON_STATE { state, spawn_count });
+            (_, ids::NET_SIGNON_STATE) => Messages::NET_SIGNON_STATE {
+                state: reader.read_int(8)?,
+                spawn_count: reader.read_int::<u32>(32)? as i32,
+            },
+            (Sender::Server, ids::SVC_PRINT) => Messages::SVC_PRINT {
+                message: reader.read_string(None)?.to_string(),
+            },
+            (Sender::Server, ids::SVC_SERVER_INFO) => Messages::SVC_SERVER_INFO {
+                protocol: reader.read_int(16)?,
+                server_count: reader.read_int(32)?,
+                hltv: reader.read_bool()?,
+                dedicated: reader.read_bool()?,
+                client_crc: reader.read_int(32)?,
+                max_classes: reader.read_int(16)?,
+                md5_map: read_md5(reader)?,
+                player_slot: reader.read_int(8)?,
+                max_clients: reader.read_int(8)?,
+                tick_interval: reader.read_float()?,
+                os: reader.read_int::<u8>(8)? as char,
+                game_dir: reader.read_string(None)?.to_string(),
+                map_name: reader.read_string(None)?.to_string(),
+                sky_name: reader.read_string(None)?.to_string(),
+                host_name: reader.read_string(None)?.to_string(),
+                replay: reader.read_bool()?,
+            },
+            (Sender::Server, ids::SVC_CREATE_STRING_TABLE) => {
+                let name = reader.read_string(None)?.to_string();
+                let max_entries = reader.read_int(16)?;
+                let num_entries = reader.read_int(entry_bits(max_entries))?;
+                let length = read_varint(reader)? as u32;
+                let user_data = if reader.read_bool()? {
+                    Some((reader.read_int(12)?, reader.read_int(4)?))
+                } else {
+                    None
+                };
+                let compressed = reader.read_bool()?;
+                Messages::SVC_CREATE_STRING_TABLE {
+                    name,
+                    max_entries,
+                    num_entries,
+                    user_data,
+                    length,
+                    data: read_bit_data(reader, length as usize)?,
+                    compressed,
+                }
+            }
+            (Sender::Server, ids::SVC_SET_VIEW) => Messages::SVC_SET_VIEW {
+                entity: reader.read_int(11)?,
+            },
+            (Sender::Server, ids::SVC_USER_MESSAGE) => {
+                let msg_type = reader.read_int(8)?;
+                let length = reader.read_int(11)?;
+                Messages::SVC_USER_MESSAGE {
+                    msg_type,
+                    length,
+                    data: read_bit_data(reader, length as usize)?,
+                }
+            }
+            (Sender::Client, ids::CLC_CLIENT_INFO) => {
+                let server_count = reader.read_int::<u32>(32)? as i32;
+                let send_table_crc = reader.read_int(32)?;
+                let hltv = reader.read_bool()?;
+                let replay = reader.read_bool()?;
+                let friends_id = reader.read_int(32)?;
+                let friends_name = reader.read_string(None)?.to_string();
+                let mut custom_files = [None; 4];
+                for file in custom_files.iter_mut() {
+                    if reader.read_bool()? {
+                        *file = Some(reader.read_int(32)?);
+                    }
+                }
+                Messages::CLC_CLIENT_INFO {
+                    server_count,
+                    send_table_crc,
+                    hltv,
+                    replay,
+                    friends_id,
+                    friends_name,
+                    custom_files,
+                }
+            }
+            (Sender::Client, ids::CLC_MOVE) => {
+                let new_commands = reader.read_int(4)?;
+                let backup_commands = reader.read_int(3)?;
+                let length = reader.read_int(16)?;
+                Messages::CLC_MOVE {
+                    new_commands,
+                    backup_commands,
+                    length,
+                    data: read_bit_data(reader, length as usize)?,
+                }
             }
-            _ => {
-                error!("An unknown message typ was encountered: {}", msg_type);
-                return Ok(vec![]);
+            (Sender::Client, ids::CLC_VOICE_DATA) => {
+                let length = reader.read_int(16)?;
+                Messages::CLC_VOICE_DATA {
+                    length,
+                    data: read_bit_data(reader, length as usize)?,
+                }
             }
+            (Sender::Client, ids::CLC_BASELINE_ACK) => Messages::CLC_BASELINE_ACK {
+                baseline_tick: reader.read_int::<u32>(32)? as i32,
+                baseline_nr: reader.read_int(1)?,
+            },
+            (Sender::Client, ids::CLC_LISTEN_EVENTS) => {
+                let mut events = [0; 16];
+                for event in events.iter_mut() {
+                    *event = reader.read_int(32)?;
+                }
+                Messages::CLC_LISTEN_EVENTS { events }
+            }
+            (Sender::Client, ids::CLC_RESPOND_CVAR_VALUE) => Messages::CLC_RESPOND_CVAR_VALUE {
+                cookie: reader.read_int::<u32>(32)? as i32,
+                // Sign extend the 4 bit status code
+                status: (reader.read_int::<u8>(4)? << 4) as i8 >> 4,
+                name: reader.read_string(None)?.to_string(),
+                value: reader.read_string(None)?.to_string(),
+            },
+            (Sender::Client, ids::CLC_FILE_CRC_CHECK) => {
+                reader.read_bool()?; // Reserved
+                let (path_id, filename) = read_file_path(reader)?;
+                Messages::CLC_FILE_CRC_CHECK {
+                    path_id,
+                    filename,
+                    md5: read_md5(reader)?,
+                    hash_type: reader.read_int(32)?,
+                    file_len: reader.read_int(32)?,
+                    pack_file_number: reader.read_int(32)?,
+                    pack_file_id: reader.read_int(32)?,
+                    file_fraction: reader.read_int(32)?,
+                }
+            }
+            (Sender::Client, ids::CLC_SAVE_REPLAY) => Messages::CLC_SAVE_REPLAY {
+                filename: reader.read_string(None)?.to_string(),
+                // The engine sizes this field with sizeof, so only 4 bits are sent
+                start_send_byte: reader.read_int(4)?,
+                post_death_record_time: reader.read_float()?,
+            },
+            (Sender::Client, ids::CLC_CMD_KEY_VALUES) => {
+                let length: u32 = reader.read_int(32)?;
+                Messages::CLC_CMD_KEY_VALUES {
+                    data: reader.read_bytes(length as usize)?.to_vec(),
+                }
+            }
+            (Sender::Client, ids::CLC_FILE_MD5_CHECK) => {
+                let (path_id, filename) = read_file_path(reader)?;
+                Messages::CLC_FILE_MD5_CHECK {
+                    path_id,
+                    filename,
+                    md5: read_md5(reader)?,
+                }
+            }
+            _ => return Ok(None),
         };
+        Ok(Some(message))
+    }
+}
+
+pub fn process_messages(
+    reader: &mut BitReadStream<LittleEndian>,
+    sender: Sender,
+) -> Result<Vec<Messages>, Box<dyn Error>> {
+    let mut messages = vec![];
+
+    while reader.bits_left() >= TYPE_BITS {
+        let msg_type: u8 = reader.read_int(TYPE_BITS)?;
+        match Messages::read(msg_type, sender, reader)? {
+            Some(message) => messages.push(message),
+            None => {
+                // Without knowing its size there's no way to find the next message
+                error!("An unknown message type was encountered: {}", msg_type);
+                break;
+            }
+        }
     }
 
     Ok(messages)
 }
+
+/// Serialize messages into a stream
+pub fn write_messages<I: IntoIterator<Item = Messages>>(
+    writer: &mut BitWriter,
+    messages: I,
+) -> Result<(), Box<dyn Error>> {
+    for message in messages {
+        message.write(writer)?;
+    }
+    Ok(())
+}
+
+/// Bits used for the entry count of a string table
+fn entry_bits(max_entries: u16) -> usize {
+    // Just enough bits to count up to max_entries
+    16 - max_entries.leading_zeros() as usize
+}
+
+fn read_md5(reader: &mut BitReadStream<LittleEndian>) -> Result<[u8; 16], Box<dyn Error>> {
+    let mut md5 = [0; 16];
+    md5.copy_from_slice(&reader.read_bytes(16)?);
+    Ok(md5)
+}
+
+/// Read a path from a file check, where common path ids and directories are sent as numbers
+fn read_file_path(
+    reader: &mut BitReadStream<LittleEndian>,
+) -> Result<(String, String), Box<dyn Error>> {
+    let path_id = match reader.read_int::<usize>(2)? {
+        0 => reader.read_string(None)?.to_string(),
+        code => COMMON_PATH_IDS.get(code - 1).unwrap_or(&"").to_string(),
+    };
+    let filename = match reader.read_int::<usize>(3)? {
+        0 => reader.read_string(None)?.to_string(),
+        code => {
+            let prefix = COMMON_PREFIXES.get(code - 1).unwrap_or(&"");
+            format!("{}/{}", prefix, reader.read_string(None)?)
+        }
+    };
+    Ok((path_id, filename))
+}
+
+/// Write a path for a file check, the filename is always sent in full
+fn write_file_path(writer: &mut BitWriter, path_id: &str, filename: &str) {
+    match COMMON_PATH_IDS.iter().position(|id| *id == path_id) {
+        Some(i) => writer.write_int(i as u8 + 1, 2),
+        None => {
+            writer.write_int(0u8, 2);
+            writer.write_string(path_id);
+        }
+    }
+    writer.write_int(0u8, 3);
+    writer.write_string(filename);
+}
+
+#[cfg(test)]
+mod tests {
+    use bitbuffer::{BitReadBuffer, BitReadStream, LittleEndian};
+    use std::collections::HashMap;
+
+    use super::{process_messages, write_messages, Messages, Sender};
+    use crate::stateful::util::BitWriter;
+
+    /// Encode messages and decode them again as if the sender had sent them
+    fn round_trip(sender: Sender, messages: Vec<Messages>) -> Vec<Messages> {
+        let count = messages.len();
+        let mut writer = BitWriter::new();
+        write_messages(&mut writer, messages).unwrap();
+        let data = writer.finish();
+        let mut reader = BitReadStream::new(BitReadBuffer::new(&data, LittleEndian));
+        let mut decoded = process_messages(&mut reader, sender).unwrap();
+        // Padding in the last byte can be long enough to read as a NOP
+        assert!(decoded.iter().skip(count).all(|m| *m == Messages::NET_NOP));
+        decoded.truncate(count);
+        decoded
+    }
+
+    /// Check that every message decodes to what was encoded on its own
+    fn assert_round_trip(sender: Sender, messages: Vec<Messages>) {
+        for message in messages {
+            assert_eq!(
+                round_trip(sender, vec![message.clone()]),
+                vec![message.clone()],
+                "{:?} didn't survive a round trip",
+                message
+            );
+        }
+    }
+
+    fn net_messages() -> Vec<Messages> {
+        let mut convars = HashMap::new();
+        convars.insert("name".to_string(), "Player".to_string());
+        convars.insert(
+            "cl_connectmethod".to_string(),
+            "serverbrowser_favorites".to_string(),
+        );
+        vec![
+            Messages::NET_NOP,
+            Messages::NET_DISCONNECT {
+                reason: "Disconnect by user.".to_string(),
+            },
+            Messages::NET_FILE {
+                transfer_id: 7,
+                filename: "download/maps/cp_dustbowl.bsp".to_string(),
+                requested: true,
+            },
+            Messages::NET_TICK {
+                tick: -1,
+                host_frametime: 150,
+                host_frametime_std_dev: 3,
+            },
+            Messages::NET_STRING_CMD {
+                command: "mm_status".to_string(),
+            },
+            Messages::NET_SET_CONVARS { convars },
+            Messages::NET_SIGNON_STATE {
+                state: 3,
+                spawn_count: -1,
+            },
+        ]
+    }
+
+    #[test]
+    fn net_messages_round_trip() {
+        assert_round_trip(Sender::Client, net_messages());
+        assert_round_trip(Sender::Server, net_messages());
+    }
+
+    #[test]
+    fn svc_messages_round_trip() {
+        assert_round_trip(
+            Sender::Server,
+            vec![
+                Messages::SVC_PRINT {
+                    message: "Welcome!\n".to_string(),
+                },
+                Messages::SVC_SERVER_INFO {
+                    protocol: 24,
+                    server_count: 1,
+                    hltv: false,
+                    dedicated: true,
+                    client_crc: 0xFFFFFFFF,
+                    max_classes: 1,
+                    md5_map: [0xAB; 16],
+                    player_slot: 0,
+                    max_clients: 24,
+                    tick_interval: 0.015,
+                    os: 'l',
+                    game_dir: "tf".to_string(),
+                    map_name: "itemtest".to_string(),
+                    sky_name: "sky_tf2_04".to_string(),
+                    host_name: "Matchmaking".to_string(),
+                    replay: false,
+                },
+                Messages::SVC_CREATE_STRING_TABLE {
+                    name: "userinfo".to_string(),
+                    max_entries: 256,
+                    num_entries: 0,
+                    user_data: Some((32, 6)),
+                    length: 0,
+                    data: vec![],
+                    compressed: false,
+                },
+                Messages::SVC_CREATE_STRING_TABLE {
+                    name: "downloadables".to_string(),
+                    max_entries: 8192,
+                    num_entries: 1,
+                    user_data: None,
+                    length: 13,
+                    data: vec![0xFF, 0x1F],
+                    compressed: true,
+                },
+                Messages::SVC_SET_VIEW { entity: 1 },
+                Messages::SVC_USER_MESSAGE {
+                    msg_type: 5,
+                    length: 20,
+                    data: vec![0x12, 0x34, 0x05],
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn clc_messages_round_trip() {
+        assert_round_trip(
+            Sender::Client,
+            vec![
+                Messages::CLC_CLIENT_INFO {
+                    server_count: 1,
+                    send_table_crc: 0xDEADBEEF,
+                    hltv: false,
+                    replay: true,
+                    friends_id: 12345,
+                    friends_name: "Player".to_string(),
+                    custom_files: [Some(1), None, Some(3), None],
+                },
+                Messages::CLC_MOVE {
+                    new_commands: 1,
+                    backup_commands: 2,
+                    length: 9,
+                    data: vec![0xFF, 0x01],
+                },
+                Messages::CLC_VOICE_DATA {
+                    length: 16,
+                    data: vec![0x01, 0x02],
+                },
+                Messages::CLC_BASELINE_ACK {
+                    baseline_tick: 100,
+                    baseline_nr: 1,
+                },
+                Messages::CLC_LISTEN_EVENTS {
+                    events: [0x5555; 16],
+                },
+                Messages::CLC_RESPOND_CVAR_VALUE {
+                    cookie: -5,
+                    status: -2,
+                    name: "sv_cheats".to_string(),
+                    value: "0".to_string(),
+                },
+                Messages::CLC_FILE_CRC_CHECK {
+                    path_id: "GAME".to_string(),
+                    filename: "materials/x.vmt".to_string(),
+                    md5: [1; 16],
+                    hash_type: 1,
+                    file_len: 100,
+                    pack_file_number: 2,
+                    pack_file_id: 3,
+                    file_fraction: 4,
+                },
+                Messages::CLC_FILE_CRC_CHECK {
+                    path_id: "custom".to_string(),
+                    filename: "scripts/x.txt".to_string(),
+                    md5: [2; 16],
+                    hash_type: 0,
+                    file_len: 0,
+                    pack_file_number: 0,
+                    pack_file_id: 0,
+                    file_fraction: 0,
+                },
+                Messages::CLC_SAVE_REPLAY {
+                    filename: "replay".to_string(),
+                    start_send_byte: 15,
+                    post_death_record_time: 5.0,
+                },
+                Messages::CLC_CMD_KEY_VALUES {
+                    data: vec![0, 1, 2, 3],
+                },
+                Messages::CLC_FILE_MD5_CHECK {
+                    path_id: "MOD".to_string(),
+                    filename: "models/x.mdl".to_string(),
+                    md5: [3; 16],
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn messages_follow_each_other() {
+        let messages = net_messages();
+        assert_eq!(round_trip(Sender::Client, messages.clone()), messages);
+    }
+
+    #[test]
+    fn unknown_type_keeps_earlier_messages() {
+        // Server messages aren't expected from clients, so decoding stops there
+        let messages = vec![
+            Messages::NET_STRING_CMD {
+                command: "mm_list".to_string(),
+            },
+            Messages::SVC_SET_VIEW { entity: 1 },
+            Messages::NET_NOP,
+        ];
+        assert_eq!(round_trip(Sender::Client, messages.clone()), messages[..1]);
+    }
+
+    #[test]
+    fn lengths_that_dont_match_their_data() {
+        let too_long_for_data = Messages::SVC_USER_MESSAGE {
+            msg_type: 1,
+            length: 17,
+            data: vec![0, 0],
+        };
+        let too_long_for_field = Messages::SVC_USER_MESSAGE {
+            msg_type: 1,
+            length: 2048,
+            data: vec![0; 256],
+        };
+        for message in &[too_long_for_data, too_long_for_field] {
+            let result = message.write(&mut BitWriter::new());
+            assert!(result.is_err(), "{:?} was written", message);
+        }
+        let longest = Messages::SVC_USER_MESSAGE {
+            msg_type: 1,
+            length: 2047,
+            data: vec![0; 256],
+        };
+        assert!(longest.write(&mut BitWriter::new()).is_ok());
+    }
+}
@@ -1,120 +1,303 @@
-use bitbuffer::{BitReadBuffer, BitReadStream, BitWriteStream, LittleEndian};
+use bitbuffer::{BitReadBuffer, BitReadStream, LittleEndian};
 use log::{debug, error, warn};
 use std::error::Error;
 use std::{collections::HashMap, convert::TryInto, net::SocketAddr};
 use tokio::net::UdpSocket;
 
-use crate::{Client, ClientState, NetChannel};
+use crate::{
+    challenge::Challenges, commands::run_command, matchmaking::MatchmakingConfig, motd::motd,
+    poller::StatusCache, Client, ClientState, NetChannel, SubChannel,
+};
+
+mod compression;
+use compression::{decompress, MAX_PAYLOAD};
 
 mod util;
 use util::*;
 
 pub mod messages;
-use messages::{process_messages, Messages};
+use messages::{process_messages, write_messages, Messages, Sender};
+
+/// The subchannel reliable data is sent on
+const SUBCHANNEL: u8 = 0;
+/// Size of each fragment of a fragmented block
+const FRAGMENT_SIZE: usize = 256;
+/// Most fragments we put in a single packet
+const MAX_FRAGMENTS_PER_PACKET: usize = 4;
+
+/// Network protocol version spoken by the game
+const PROTOCOL_VERSION: u16 = 24;
+/// Identifies the current "map", which never changes
+const SERVER_COUNT: u32 = 1;
+
+// Signon states the client moves through as it connects
+const SIGNONSTATE_CONNECTED: u8 = 2;
+const SIGNONSTATE_NEW: u8 = 3;
+const SIGNONSTATE_PRESPAWN: u8 = 4;
+
+/// String tables the engine expects to exist, with their max entries and whether they are precache tables
+const STRING_TABLES: [(&str, u16, bool); 9] = [
+    ("downloadables", 8192, false),
+    ("modelprecache", 1024, true),
+    ("genericprecache", 512, true),
+    ("soundprecache", 16384, true),
+    ("decalprecache", 512, true),
+    ("instancebaseline", 1024, false),
+    ("lightstyles", 64, false),
+    ("userinfo", 256, false),
+    ("server_query_info", 4, false),
+];
 
 pub async fn handle_stateful(
+    config: &MatchmakingConfig,
+    status: &StatusCache,
     clients: &mut HashMap<SocketAddr, Client>,
+    challenges: &mut Challenges,
     sock: &mut UdpSocket,
     addr: SocketAddr,
     data: &[u8],
 ) {
+    if data.len() < 16 {
+        error!("Received packet was smaller than expected");
+        return;
+    }
+
+    // Read header data
+    let seq = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let ack = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let flags = data[8];
+    let checksum = u16::from_le_bytes(data[9..11].try_into().unwrap());
+    // Verify the checksum before we continue
+    if valve_checksum(&data[11..]) != checksum {
+        warn!("Valve checksum failed to verify");
+        return;
+    }
+    let rel = data[11];
+    let mut off = 12;
+    let _choked = if flags & 0x10 != 0 {
+        off += 1;
+        Some(data[12])
+    } else {
+        None
+    };
+    // Challenge is required to send a reply
+    if flags & 0x20 == 0 {
+        error!("Stateful packet was missing challenge");
+        return;
+    }
+    off += 4;
+    if data.len() < off {
+        error!("Received packet was smaller than its header");
+        return;
+    }
+    let challenge = u32::from_le_bytes(data[(off - 4)..off].try_into().unwrap());
+
     // Get the client/victim
     let victim = match clients.get_mut(&addr) {
-        Some(victim) => victim,
+        Some(victim) if victim.challenge == Some(challenge) => victim,
+        Some(_) => {
+            debug!("Dropping packet from {} with the wrong challenge", addr);
+            return;
+        }
         None => {
+            // Only addresses that went through the handshake get to be clients
+            if !challenges.verify(&addr, challenge) {
+                debug!("Refusing new client from {}, bad challenge", addr);
+                return;
+            }
+            if !config.limits().admits(clients, &addr) {
+                debug!("Refusing new client from {}, too many clients", addr);
+                return;
+            }
             // Welcome our client to netchannel
             let mut buffer = Vec::new();
             buffer.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
             buffer.push(0x42); // Type
             buffer.extend_from_slice("00000000000000\0".as_bytes()); // Padding
-            sock.send_to(&buffer, addr).await.unwrap();
+            if let Err(e) = sock.send_to(&buffer, addr).await {
+                error!("Failure to send packet: {}", e);
+                return;
+            }
             // Create the client state since one doesn't exist
-            clients.insert(addr, Client::new());
+            let mut client = Client::new();
+            client.challenge = Some(challenge);
+            clients.insert(addr, client);
             clients.get_mut(&addr).unwrap()
         }
     };
-
-    if data.len() < 16 {
-        error!("Received packet was smaller than expected");
+    // Late and repeated packets would move our acknowledgement backwards
+    if seq <= victim.in_seq {
+        debug!("Dropping out of order packet {} from {}", seq, addr);
         return;
     }
 
-    // Read header data
-    let seq = u32::from_le_bytes(data[0..4].try_into().unwrap());
-    let mut ack = u32::from_le_bytes(data[4..8].try_into().unwrap());
-    let flags = data[8];
-    let checksum = u16::from_le_bytes(data[9..11].try_into().unwrap());
-    // Verify the checksum before we continue
-    if valve_checksum(&data[11..]) == checksum {
-        // let rel = data[11];
-        let mut off = 12;
-        let _choked = if flags & 0x10 != 0 {
-            off += 1;
-            Some(data[12])
-        } else {
-            None
-        };
-        let challenge = if flags & 0x20 != 0 {
-            off += 4;
-            Some(u32::from_le_bytes(data[(off - 4)..off].try_into().unwrap()))
-        } else {
-            None
-        };
-
-        // Challenge is required to send a reply
-        if challenge.is_none() {
-            error!("Stateful packet was missing challenge");
-            return;
+    let result = || -> Option<Vec<Messages>> {
+        let mut msgs = vec![];
+
+        let read_buf = BitReadBuffer::new(&data[off..], LittleEndian);
+        let mut reader = BitReadStream::new(read_buf);
+        if flags & 0x01 != 0 {
+            // Check which bit in the reliable state we need to flip
+            victim.flip_rel(reader.read_int(3).ok()?);
+            // Read both subchannels
+            for stream_num in 0..2 {
+                msgs.extend(
+                    parse_subchannel(
+                        &mut reader,
+                        &mut victim.netchannels[stream_num],
+                        &mut victim.queued,
+                    )
+                    .ok()?,
+                );
+            }
         }
+        msgs.extend(process_messages(&mut reader, Sender::Client).ok()?);
+
+        Some(msgs)
+    }();
+
+    if let Some(msgs) = result {
+        // Only packets that make sense keep the client from timing out
+        victim.touch();
+        victim.in_seq = seq;
+        victim.out_seq = victim.out_seq.max(ack);
+        process_ack(victim, ack, rel);
+        let mut replies = handle_messages(config, status, clients, addr, msgs);
+        // The client may have disconnected while handling its messages
+        if let Some(client) = clients.get_mut(&addr) {
+            client.queued.append(&mut replies);
+            send_pending(sock, addr, client, true).await;
+        }
+    } else {
+        error!("Failed to parse packet data");
+    }
+}
 
-        let result = || -> Option<Vec<Messages>> {
-            let mut msgs = vec![];
-
-            let read_buf = BitReadBuffer::new(&data[off..], LittleEndian);
-            let mut reader = BitReadStream::new(read_buf);
-            if flags & 0x01 != 0 {
-                // Check which bit in the reliable state we need to flip
-                victim.flip_rel(reader.read_int(3).ok()?);
-                // Read both subchannels
-                for stream_num in 0..2 {
-                    msgs.extend(
-                        parse_subchannel(&mut reader, &mut victim.netchannels[stream_num]).ok()?,
-                    );
-                }
-            }
-            msgs.extend(process_messages(&mut reader).ok()?);
-
-            Some(msgs)
-        }();
-
-        if let Some(msgs) = result {
-            let reliable = victim.reliable;
-            let packets = build_packets(
-                handle_messages(clients, addr, msgs),
-                &mut ack,
-                seq,
-                reliable,
-                challenge.unwrap(),
-            );
-
-            for packet in packets {
-                match sock.send_to(&packet, addr).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Failure to send packet: {}", e);
-                    }
-                }
+/// Check whether the client received the reliable data we last sent it, which is only sent again
+/// once an ack shows it was lost, since a client that already has it would process it twice
+fn process_ack(client: &mut Client, ack: u32, rel: u8) {
+    let bit = 1 << SUBCHANNEL;
+    let lost = match &client.sending {
+        Some(SubChannel {
+            send_seq,
+            sent: true,
+            ..
+        }) if *send_seq <= ack => rel & bit != client.out_reliable & bit,
+        _ => return,
+    };
+    let sub = client.sending.as_mut().unwrap();
+    if lost {
+        // Undo the flip so the data goes out with the same state again
+        client.out_reliable ^= bit;
+        sub.sent = false;
+    } else if (sub.start + sub.count) * FRAGMENT_SIZE < sub.data.len() {
+        // Move on to the fragments that haven't been sent yet
+        sub.start += sub.count;
+        sub.count = 0;
+        sub.sent = false;
+    } else {
+        client.sending = None;
+    }
+}
+
+/// Send any reliable data waiting for a client, or an empty packet if a reply is required anyway
+async fn send_pending(sock: &mut UdpSocket, addr: SocketAddr, client: &mut Client, reply: bool) {
+    // Packets without the challenge the client expects are ignored
+    let challenge = match client.challenge {
+        Some(challenge) => challenge,
+        None => return,
+    };
+    let bit = 1 << SUBCHANNEL;
+
+    // Start a new block once the last one was received
+    if client.sending.is_none() && !client.queued.is_empty() {
+        let mut writer = BitWriter::new();
+        match write_messages(&mut writer, client.queued.drain(..)) {
+            Ok(()) => {
+                client.sending = Some(SubChannel {
+                    data: writer.finish(),
+                    start: 0,
+                    count: 0,
+                    send_seq: 0,
+                    sent: false,
+                })
             }
-        } else {
-            error!("Failed to parse packet data");
+            Err(e) => error!(
+                "Dropping messages for {} that can't be written: {}",
+                addr, e
+            ),
         }
+    }
+
+    let mut reliable = client.sending.as_mut().filter(|sub| !sub.sent);
+    if reliable.is_none() && !reply {
+        return;
+    }
+    if let Some(sub) = reliable.as_mut() {
+        // Big blocks are sent a few fragments at a time
+        let remaining = fragment_count(sub.data.len()) - sub.start;
+        sub.count = remaining.min(MAX_FRAGMENTS_PER_PACKET);
+    }
+    let packets = build_packets(
+        reliable.as_deref(),
+        &mut client.out_seq,
+        client.in_seq,
+        client.reliable,
+        challenge,
+    );
+    if let Some(sub) = reliable {
+        client.out_reliable ^= bit;
+        sub.send_seq = client.out_seq;
+        sub.sent = true;
+    }
+    for packet in packets {
+        if let Err(e) = sock.send_to(&packet, addr).await {
+            error!("Failure to send packet: {}", e);
+        }
+    }
+}
+
+/// Send queued messages to every client without waiting for them to send us a packet
+pub async fn flush_queued(clients: &mut HashMap<SocketAddr, Client>, sock: &mut UdpSocket) {
+    for (addr, client) in clients.iter_mut() {
+        send_pending(sock, *addr, client, false).await;
+    }
+}
+
+/// Number of fragments needed to send a block
+fn fragment_count(length: usize) -> usize {
+    length.div_ceil(FRAGMENT_SIZE)
+}
+
+/// Write the next part of a reliable block to a packet
+fn write_subchannel(writer: &mut BitWriter, sub: &SubChannel) {
+    writer.write_int(SUBCHANNEL, 3);
+    // Everything goes out on the first stream
+    writer.write_bool(true);
+    let length = sub.data.len();
+    if fragment_count(length) <= 1 {
+        writer.write_bool(false); // Single block
+        writer.write_bool(false); // Not compressed
+        write_varint(writer, length);
+        writer.write_bytes(&sub.data);
     } else {
-        warn!("Valve checksum failed to verify");
+        writer.write_bool(true); // Fragmented
+        writer.write_int(sub.start as u32, 18);
+        writer.write_int(sub.count as u8, 3);
+        if sub.start == 0 {
+            writer.write_bool(false); // Not a file
+            writer.write_bool(false); // Not compressed
+            writer.write_int(length as u32, 26);
+        }
+        let begin = sub.start * FRAGMENT_SIZE;
+        let end = ((sub.start + sub.count) * FRAGMENT_SIZE).min(length);
+        writer.write_bytes(&sub.data[begin..end]);
     }
+    writer.write_bool(false); // Nothing on the file stream
 }
 
 fn build_packets(
-    messages: Vec<Messages>,
+    reliable: Option<&SubChannel>,
     seq: &mut u32,
     ack: u32,
     rel: u8,
@@ -123,24 +306,11 @@ fn build_packets(
     let mut packets = vec![];
     *seq += 1;
 
-    let mut writer = BitWriteStream::new(LittleEndian);
-    for message in messages {
-        match message {
-            Messages::NET_DISCONNECT { reason } => {
-                writer.write_int(1u8, 6).unwrap();
-                writer.write_string(&reason, None).unwrap();
-            }
-            Messages::SVC_PRINT { message } => {
-                writer.write_int(7u8, 6).unwrap();
-                writer.write_string(&message, None).unwrap();
-            }
-            Messages::SVC_STRING_CMD { command } => {
-                writer.write_int(4u8, 6).unwrap();
-                writer.write_string(&command, None).unwrap();
-            }
-            Messages::NET_NOP => {}
-            _ => error!("Expected to serialize unknown message: {:#?}", message),
-        }
+    let mut flags = 0x20;
+    let mut writer = BitWriter::new();
+    if let Some(sub) = reliable {
+        flags |= 0x01;
+        write_subchannel(&mut writer, sub);
     }
     // Encapsulate packet
     let packet = writer.finish();
@@ -149,7 +319,7 @@ fn build_packets(
     let full_packet = [
         &seq.to_le_bytes() as &[u8],
         &ack.to_le_bytes(),
-        &[0x20],
+        &[flags],
         &valve_checksum(&body).to_le_bytes(),
         &body,
     ]
@@ -160,20 +330,63 @@ fn build_packets(
     packets
 }
 
+/// Everything a client needs to move on from the connected state
+fn server_info(config: &MatchmakingConfig) -> Vec<Messages> {
+    let mut messages = vec![Messages::SVC_SERVER_INFO {
+        protocol: PROTOCOL_VERSION,
+        server_count: SERVER_COUNT,
+        hltv: false,
+        dedicated: true,
+        client_crc: 0xFFFFFFFF,
+        max_classes: 1,
+        md5_map: [0; 16],
+        player_slot: 0,
+        max_clients: config.max_players(),
+        tick_interval: 0.015,
+        os: 'l',
+        game_dir: "tf".to_string(),
+        map_name: config.map(),
+        sky_name: "sky_tf2_04".to_string(),
+        host_name: config.hostname(),
+        replay: false,
+    }];
+    // Nothing is ever precached, so every table starts out empty
+    for (name, max_entries, precache) in STRING_TABLES.iter() {
+        messages.push(Messages::SVC_CREATE_STRING_TABLE {
+            name: name.to_string(),
+            max_entries: *max_entries,
+            num_entries: 0,
+            length: 0,
+            // Precache entries carry a fixed two bits of flags
+            user_data: if *precache { Some((1, 2)) } else { None },
+            data: vec![],
+            compressed: false,
+        });
+    }
+    messages.push(Messages::NET_SIGNON_STATE {
+        state: SIGNONSTATE_NEW,
+        spawn_count: SERVER_COUNT as i32,
+    });
+    messages
+}
+
 fn handle_messages(
+    config: &MatchmakingConfig,
+    status: &StatusCache,
     clients: &mut HashMap<SocketAddr, Client>,
     addr: SocketAddr,
     messages: Vec<Messages>,
 ) -> Vec<Messages> {
     let mut results = Vec::new();
-    let client = match clients.get_mut(&addr) {
-        Some(c) => c,
-        None => {
-            warn!("Attempt to handle messages for client that doesn't exist");
-            return vec![];
-        }
-    };
     for msg in messages {
+        // Looked up for every message so that handlers can also look at the other clients
+        let client = match clients.get_mut(&addr) {
+            Some(c) => c,
+            None => {
+                warn!("Attempt to handle messages for client that doesn't exist");
+                return vec![];
+            }
+        };
         match msg {
             Messages::NET_DISCONNECT { reason } => {
                 if let Some(name) = client.name() {
@@ -184,21 +397,80 @@ fn handle_messages(
                 clients.remove(&addr);
                 return vec![];
             }
+            Messages::NET_FILE {
+                transfer_id,
+                filename,
+                requested: true,
+            } => {
+                // There are no files to hand out here
+                debug!("Denying request for file {}", filename);
+                results.push(Messages::NET_FILE {
+                    transfer_id,
+                    filename,
+                    requested: false,
+                });
+            }
+            Messages::NET_SIGNON_STATE { state, spawn_count } => {
+                // Clients may always start over, otherwise they have to be in step with us
+                if state != SIGNONSTATE_CONNECTED
+                    && (state != client.signon || spawn_count != SERVER_COUNT as i32)
+                {
+                    debug!(
+                        "Client is out of step in signon state {}, restarting",
+                        state
+                    );
+                    client.signon = SIGNONSTATE_CONNECTED;
+                    results.push(Messages::NET_SIGNON_STATE {
+                        state: SIGNONSTATE_CONNECTED,
+                        spawn_count: -1,
+                    });
+                    continue;
+                }
+                match state {
+                    SIGNONSTATE_CONNECTED => {
+                        results.extend(server_info(config));
+                        client.signon = SIGNONSTATE_NEW;
+                        // Clients being turned away don't need to hear about the queue
+                        if client.state == ClientState::Confirmed {
+                            results.push(Messages::SVC_PRINT {
+                                message: motd(config, status, clients, addr),
+                            });
+                        }
+                    }
+                    SIGNONSTATE_NEW => {
+                        results.push(Messages::NET_SIGNON_STATE {
+                            state: SIGNONSTATE_PRESPAWN,
+                            spawn_count: SERVER_COUNT as i32,
+                        });
+                        client.signon = SIGNONSTATE_PRESPAWN;
+                    }
+                    // The client waits at prespawn until it is redirected
+                    _ => {}
+                }
+            }
+            Messages::NET_STRING_CMD { command } => {
+                if let Some(message) = run_command(config, status, clients, addr, &command) {
+                    results.push(Messages::SVC_PRINT { message });
+                }
+            }
             Messages::NET_SET_CONVARS { convars } => {
-                if let Some(name) = convars.get("name") {
-                    client.set_name(name.to_string());
+                // Players can set their party code with "setinfo mm_party <code>"
+                if let Some(code) = convars.get("mm_party").filter(|c| !c.is_empty()) {
+                    client.set_party(code.to_string());
+                }
+                // Keep everything, the matchmaker reads the player's preferences from it
+                client.set_convars(convars);
+                if let Some(name) = client.name() {
                     debug!("{} joined", name);
                 } else {
                     warn!("An unknown client joined");
                 }
-                if let Some(method) = convars.get("cl_connectmethod") {
-                    if method == "serverbrowser_favorites" {
-                        client.state = ClientState::Confirmed;
-                    } else {
-                        results.push(Messages::NET_DISCONNECT {
-                            reason: "You must join this server from the favorites tab!".to_string(),
-                        });
-                    }
+                // Later updates, like a name change, must not undo a redirect
+                if client.state != ClientState::Fresh {
+                    continue;
+                }
+                if client.convar("cl_connectmethod") == Some("serverbrowser_favorites") {
+                    client.state = ClientState::Confirmed;
                 } else {
                     results.push(Messages::NET_DISCONNECT {
                         reason: "You must join this server from the favorites tab!".to_string(),
@@ -208,97 +480,98 @@ fn handle_messages(
             _ => {}
         }
     }
-    if let Some(r) = client.queued.pop() {
-        results.push(r);
-    }
     results
 }
 
 fn parse_subchannel(
     reader: &mut BitReadStream<LittleEndian>,
     netchannel: &mut NetChannel,
+    replies: &mut Vec<Messages>,
 ) -> Result<Vec<Messages>, Box<dyn Error>> {
     // Check if the subchannel exists
-    if reader.read_bool().unwrap() {
+    if reader.read_bool()? {
         // Is this part of a multi-block structure?
-        let multi = reader.read_bool().unwrap();
+        let multi = reader.read_bool()?;
         if multi {
             let start_fragment: u32 = reader.read_int(18)?;
             let num_fragments: u8 = reader.read_int(3)?;
 
             if start_fragment == 0 {
                 // Is the fragment a file?
-                let _filename = if reader.read_bool()? {
+                let file = if reader.read_bool()? {
                     // Tranfer id, filename
-                    Some((reader.read_int::<u32>(32)?, reader.read_string(None)?))
+                    let transfer_id = reader.read_int::<u32>(32)?;
+                    Some((transfer_id, reader.read_string(None)?.to_string()))
                 } else {
                     None
                 };
 
                 // Is the fragment compressed?
                 let compressed = if reader.read_bool()? {
-                    Some(reader.read_int::<u32>(26))
+                    Some(reader.read_int::<u32>(26)? as usize)
                 } else {
                     None
                 };
 
-                if compressed.is_some() {
-                    warn!("Client attempted to send compressed fragmented netchannel");
-                    return Ok(vec![
-						Messages::NET_DISCONNECT { reason: "Your client sent data we couldn't understand. We will try to fix this soon!".to_string() }
-					]);
-                }
-
                 let total_length: u32 = reader.read_int(26)?;
-
-                let mut total_fragments = total_length as usize / 256;
-                if total_length % 256 != 0 {
-                    total_fragments += 1;
-                }
-
-                *netchannel = NetChannel {
-                    fragments: vec![vec![]; total_fragments],
-                    num_fragments: total_fragments,
-                    length: total_length as usize,
-                };
-
-                if total_fragments < num_fragments as usize {
-                    error!("More fragments were received than expected");
-                    return Ok(vec![]);
-                }
-
-                for i in 0..num_fragments {
-                    let fragment = reader.read_bytes(256)?;
-                    netchannel.fragments[start_fragment as usize + i as usize] = fragment.to_vec();
-                }
-            } else if start_fragment as usize + num_fragments as usize == netchannel.num_fragments {
-                if netchannel.fragments.len() < start_fragment as usize + num_fragments as usize {
-                    error!("More fragments were received than expected");
-                    return Ok(vec![]);
+                let total_fragments = fragment_count(total_length as usize);
+
+                if let Some((transfer_id, filename)) = file {
+                    // We have no use for uploads, so the data is skipped instead of stored
+                    debug!("Refusing file {} from client", filename);
+                    replies.push(Messages::NET_FILE {
+                        transfer_id,
+                        filename,
+                        requested: false,
+                    });
+                    *netchannel = NetChannel {
+                        compressed: None,
+                        file: Some(transfer_id),
+                        fragments: vec![],
+                        num_fragments: total_fragments,
+                        length: total_length as usize,
+                    };
+                } else {
+                    if total_length as usize > MAX_PAYLOAD {
+                        error!("Client announced a block too large to accept");
+                        return Ok(vec![]);
+                    }
+                    *netchannel = NetChannel {
+                        compressed,
+                        file: None,
+                        fragments: vec![vec![]; total_fragments],
+                        num_fragments: total_fragments,
+                        length: total_length as usize,
+                    };
                 }
+            }
 
-                for i in 0..num_fragments - 1 {
-                    let fragment = reader.read_bytes(256)?;
-                    netchannel.fragments[start_fragment as usize + i as usize] = fragment.to_vec();
-                }
-                netchannel.fragments[start_fragment as usize + num_fragments as usize - 1] =
-                    reader.read_bytes(netchannel.length % 256)?.to_vec();
-            } else {
-                if netchannel.fragments.len() < start_fragment as usize + num_fragments as usize {
-                    error!("More fragments were received than expected");
-                    return Ok(vec![]);
-                }
+            if netchannel.num_fragments < start_fragment as usize + num_fragments as usize {
+                error!("More fragments were received than expected");
+                return Ok(vec![]);
+            }
 
-                for i in 0..num_fragments {
-                    let fragment = reader.read_bytes(256)?;
-                    netchannel.fragments[start_fragment as usize + i as usize] = fragment.to_vec();
+            for i in start_fragment as usize..start_fragment as usize + num_fragments as usize {
+                // Only the last fragment may be shorter than the rest
+                let size = if i + 1 == netchannel.num_fragments {
+                    netchannel.length - i * FRAGMENT_SIZE
+                } else {
+                    FRAGMENT_SIZE
+                };
+                if netchannel.file.is_some() {
+                    reader.skip_bits(size * 8)?;
+                } else {
+                    netchannel.fragments[i] = reader.read_bytes(size)?.to_vec();
                 }
             }
+            if netchannel.file.is_some() {
+                return Ok(vec![]);
+            }
 
             // Check if all fragments have arived
             let mut done = true;
             for fragment in &netchannel.fragments {
-                if fragment.len() == 0 {
+                if fragment.is_empty() {
                     done = false;
                 }
             }
@@ -307,10 +580,13 @@ fn parse_subchannel(
                 for fragment in &netchannel.fragments {
                     data.extend(fragment);
                 }
+                if let Some(size) = netchannel.compressed {
+                    data = decompress(&data, size)?;
+                }
                 let msg_buf = BitReadBuffer::new(&data, LittleEndian);
                 let mut msg_reader = BitReadStream::new(msg_buf);
 
-                let msgs = process_messages(&mut msg_reader)?;
+                let msgs = process_messages(&mut msg_reader, Sender::Client)?;
                 Ok(msgs)
             } else {
                 Ok(vec![Messages::NET_NOP])
@@ -318,30 +594,56 @@ fn parse_subchannel(
         } else {
             // Is the data compressed?
             let compressed = if reader.read_bool()? {
-                Some(reader.read_int::<u32>(26)?)
+                Some(reader.read_int::<u32>(26)? as usize)
             } else {
                 None
             };
 
-            if compressed.is_some() {
-                warn!("Client attempted to send compressed single block netchannel");
-                return Ok(vec![
-					Messages::NET_DISCONNECT { reason: "Your client sent data we couldn't understand. We will try to fix this soon!".to_string() }
-				]);
-            }
-
             // What is the length of the message?
             let len = read_varint(reader)?;
             // Finally, the message itself.
-            let msg = reader.read_bytes(len)?;
+            let mut msg = reader.read_bytes(len)?.to_vec();
+            if let Some(size) = compressed {
+                msg = decompress(&msg, size)?;
+            }
 
             let msg_buf = BitReadBuffer::new(&msg, LittleEndian);
             let mut msg_reader = BitReadStream::new(msg_buf);
 
-            let msgs = process_messages(&mut msg_reader)?;
+            let msgs = process_messages(&mut msg_reader, Sender::Client)?;
             Ok(msgs)
         }
     } else {
         Ok(vec![])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitbuffer::{BitReadBuffer, BitReadStream, LittleEndian};
+
+    use super::{parse_subchannel, util::write_varint, util::BitWriter};
+    use crate::Client;
+
+    #[test]
+    fn packet_ending_after_first_stream() {
+        let mut writer = BitWriter::new();
+        writer.write_int(0u8, 3); // Reliable state bit to flip
+        writer.write_bool(true); // Data on the first stream
+        writer.write_bool(false); // Single block
+        writer.write_bool(true); // Compressed
+        writer.write_int(0u32, 26);
+        write_varint(&mut writer, 5);
+        writer.write_bytes(b"SNAP\0"); // Nothing, compressed
+        let data = writer.finish();
+        let mut reader = BitReadStream::new(BitReadBuffer::new(&data, LittleEndian));
+        reader.read_int::<u8>(3).unwrap();
+        let mut client = Client::new();
+        let mut replies = vec![];
+        let first = parse_subchannel(&mut reader, &mut client.netchannels[0], &mut replies);
+        assert_eq!(first.unwrap(), vec![]);
+        // The packet ends where the second stream's bit should be
+        assert_eq!(reader.bits_left(), 0);
+        assert!(parse_subchannel(&mut reader, &mut client.netchannels[1], &mut replies).is_err());
+    }
+}
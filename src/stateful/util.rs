@@ -1,6 +1,27 @@
 use bitbuffer::{BitReadStream, LittleEndian};
 use crc::crc32;
-use std::error::Error;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+#[derive(Debug)]
+pub enum WriteError {
+    DataTooShort,
+    LengthTooLong,
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for WriteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
 
 pub fn read_varint(reader: &mut BitReadStream<LittleEndian>) -> Result<usize, Box<dyn Error>> {
     let mut count = 0;
@@ -14,6 +35,98 @@ pub fn read_varint(reader: &mut BitReadStream<LittleEndian>) -> Result<usize, Bo
     Ok(result)
 }
 
+/// Read a number of bits into bytes, with any leftover bits in the last byte
+pub fn read_bit_data(
+    reader: &mut BitReadStream<LittleEndian>,
+    bits: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::with_capacity(bits.div_ceil(8));
+    for i in (0..bits).step_by(8) {
+        data.push(reader.read_int((bits - i).min(8))?);
+    }
+    Ok(data)
+}
+
+/// Write a number of bits from bytes read with read_bit_data
+pub fn write_bit_data(
+    writer: &mut BitWriter,
+    data: &[u8],
+    bits: usize,
+) -> Result<(), Box<dyn Error>> {
+    // The length is kept apart from the data, so make sure the two agree
+    if bits > data.len() * 8 {
+        return Err(Box::new(WriteError::DataTooShort));
+    }
+    for i in (0..bits).step_by(8) {
+        writer.write_int(data[i / 8], (bits - i).min(8));
+    }
+    Ok(())
+}
+
+pub fn write_varint(writer: &mut BitWriter, mut value: usize) {
+    while value > 0x7F {
+        writer.write_int(((value & 0x7F) | 0x80) as u8, 8);
+        value >>= 7;
+    }
+    writer.write_int(value as u8, 8);
+}
+
+/// Little endian bit writer
+///
+/// bitbuffer's BitWriteStream clobbers the previous byte whenever a write starts on a byte
+/// boundary, so packets are written with this instead.
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: vec![],
+            bit_len: 0,
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        if self.bit_len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if value {
+            *self.bytes.last_mut().unwrap() |= 1 << (self.bit_len % 8);
+        }
+        self.bit_len += 1;
+    }
+
+    /// Write the lowest `count` bits of an integer
+    pub fn write_int<T: Into<u64>>(&mut self, value: T, count: usize) {
+        let value = value.into();
+        for i in 0..count {
+            self.write_bool(value >> i & 1 != 0);
+        }
+    }
+
+    pub fn write_float(&mut self, value: f32) {
+        self.write_int(value.to_bits(), 32);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_int(*byte, 8);
+        }
+    }
+
+    /// Write a null terminated string
+    pub fn write_string(&mut self, string: &str) {
+        self.write_bytes(string.as_bytes());
+        self.write_int(0u8, 8);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
 pub fn valve_checksum(data: &[u8]) -> u16 {
     let mut result = crc32::checksum_ieee(data);
     result ^= result >> 16;
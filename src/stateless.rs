@@ -1,28 +1,31 @@
+use log::debug;
 use std::{
-    convert::TryInto,
-    error::Error,
-    net::SocketAddr,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::HashMap, convert::TryInto, error::Error, net::SocketAddr, time::SystemTime,
 };
 use tokio::net::UdpSocket;
 
-use crate::matchmaking::MatchmakingConfig;
-
-pub fn generate_challenge() -> u32 {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    (now & 0xFFFFFFFF) as u32
-}
+use crate::{
+    challenge::{Challenges, NO_CHALLENGE},
+    matchmaking::{rank_servers, MatchmakingConfig},
+    poller::StatusCache,
+    split::split_packet,
+    Client,
+};
 
 pub async fn handle_stateless(
     config: &MatchmakingConfig,
+    status: &StatusCache,
+    clients: &mut HashMap<SocketAddr, Client>,
+    challenges: &mut Challenges,
     sock: &mut UdpSocket,
     addr: SocketAddr,
     data: &[u8],
 ) -> Result<(), Box<dyn Error>> {
     let mut response = Vec::new();
+    // Every request past this point carries at least a challenge
+    if data.len() < 9 && data[4] != 0x54 {
+        return Ok(());
+    }
     match data[4] {
         0x54 => {
             // A2S_INFO
@@ -34,8 +37,8 @@ pub async fn handle_stateless(
             response.extend_from_slice("tf\0".as_bytes()); // Game folder
             response.extend_from_slice("Team Fortress 2\0".as_bytes()); // Game name
             response.extend_from_slice(&440u16.to_le_bytes()); // Game ID
-            response.push(0); // Number of players
-            response.push(24); // Max players
+            response.push(clients.len().min(255) as u8); // Number of players
+            response.push(config.max_players()); // Max players
             response.push(0); // Number of bots
             response.push(0x64); // Server type(Dedicated)
             response.push(0x6C); // Server environment(Linux)
@@ -43,7 +46,7 @@ pub async fn handle_stateless(
             response.push(0); // VAC Support(Disabled)
             response.extend_from_slice("0\0".as_bytes()); // Game version
             response.push(0xA1); // Extra Data Flags
-            response.extend_from_slice(&config.bind_addr().1.to_le_bytes()); // Port number
+            response.extend_from_slice(&config.bind_addr().port().to_le_bytes()); // Port number
             response.extend_from_slice("breadpudding,matchmaking\0".as_bytes()); // Keywords
             response.extend_from_slice(&440u64.to_le_bytes()); // Game ID
         }
@@ -51,35 +54,89 @@ pub async fn handle_stateless(
             // A2S_PLAYER
             response.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
             let challenge = u32::from_le_bytes(data[5..9].try_into().unwrap());
-            if challenge == 0xFFFFFFFF {
+            if challenge == NO_CHALLENGE || !challenges.verify(&addr, challenge) {
                 response.push(0x41); // Type
-                response.extend_from_slice(&generate_challenge().to_le_bytes());
+                response.extend_from_slice(&challenges.issue(&addr).to_le_bytes());
             // Challenge
             } else {
                 response.push(0x44); // Type
-                response.push(0); // Number of players
+                let mut players: Vec<&Client> = clients.values().collect();
+                players.sort_by_key(|p| p.joined());
+                players.truncate(255);
+                response.push(players.len() as u8); // Number of players
+                let now = SystemTime::now();
+                for (index, p) in players.into_iter().enumerate() {
+                    let waited = now.duration_since(p.joined()).unwrap_or_default();
+                    response.push(index as u8); // Index
+                    let name = p.name().unwrap_or_else(|| "Connecting...".to_string());
+                    response.extend_from_slice(format!("{}\0", name).as_bytes()); // Name
+                    response.extend_from_slice(&0i32.to_le_bytes()); // Score
+                    response.extend_from_slice(&waited.as_secs_f32().to_le_bytes());
+                    // Duration
+                }
             }
         }
         0x56 => {
             // A2S_RULES
             response.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
             let challenge = u32::from_le_bytes(data[5..9].try_into().unwrap());
-            if challenge == 0xFFFFFFFF {
-                response.push(0x4A); // Type
-                response.extend_from_slice(&generate_challenge().to_le_bytes());
+            if challenge == NO_CHALLENGE || !challenges.verify(&addr, challenge) {
+                response.push(0x41); // Type
+                response.extend_from_slice(&challenges.issue(&addr).to_le_bytes());
             // Challenge
             } else {
                 response.push(0x45); // Type
-                response.push(0); // Number of rules
+                let ranked = rank_servers(config, status);
+                let average = if ranked.is_empty() {
+                    0.0
+                } else {
+                    ranked.iter().map(|c| c.score as f64).sum::<f64>() / ranked.len() as f64
+                };
+                let mut rules = vec![
+                    ("mm_version", env!("CARGO_PKG_VERSION").to_string()),
+                    ("mm_servers", config.servers().len().to_string()),
+                    ("mm_available", ranked.len().to_string()),
+                    (
+                        "mm_best",
+                        ranked.first().map(|c| c.name.clone()).unwrap_or_default(),
+                    ),
+                    ("mm_average_score", format!("{:.2}", average)),
+                    ("mm_queue", clients.len().to_string()),
+                ];
+                let mut custom: Vec<(&String, &String)> = config.rules().iter().collect();
+                custom.sort();
+                rules.extend(custom.into_iter().map(|(k, v)| (k.as_str(), v.clone())));
+                response.extend_from_slice(&(rules.len() as u16).to_le_bytes()); // Number of rules
+                for (name, value) in rules {
+                    response.extend_from_slice(format!("{}\0", name).as_bytes()); // Name
+                    response.extend_from_slice(format!("{}\0", value).as_bytes());
+                    // Value
+                }
             }
         }
         0x6B => {
             // C2S_CONNECT
+            if data.len() < 21 {
+                return Ok(());
+            }
+            let server_challenge = u32::from_le_bytes(data[13..17].try_into().unwrap());
             let challenge = u32::from_le_bytes(data[17..21].try_into().unwrap());
             response.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
-            response.push(0x42); // Type
-            response.extend_from_slice(&challenge.to_le_bytes()); // Challenge
-            response.extend_from_slice("0000000000\0".as_bytes()); // Padding
+            if challenges.verify(&addr, server_challenge) {
+                // A reconnecting player starts a new netchannel, so whatever we knew goes
+                if clients.remove(&addr).is_some() {
+                    debug!("{} reconnected, starting over", addr);
+                }
+                response.push(0x42); // Type
+                response.extend_from_slice(&challenge.to_le_bytes()); // Challenge
+                response.extend_from_slice("0000000000\0".as_bytes()); // Padding
+            } else {
+                debug!("Rejected connection from {} with a bad challenge", addr);
+                response.push(0x39); // Type
+                response.extend_from_slice(&challenge.to_le_bytes()); // Challenge
+                response.extend_from_slice("#GameUI_ServerRejectBadChallenge\0".as_bytes());
+                // Reason
+            }
         }
         0x71 => {
             // A2S_GETCHALLENGE
@@ -87,7 +144,7 @@ pub async fn handle_stateless(
             response.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
             response.push(0x41); // Type
             response.extend_from_slice(&0x5A4F4933u32.to_le_bytes()); // Magic version
-            response.extend_from_slice(&generate_challenge().to_le_bytes()); // Server's challenge
+            response.extend_from_slice(&challenges.issue(&addr).to_le_bytes()); // Server's challenge
             response.extend_from_slice(&challenge.to_le_bytes()); // Client's challenge
             response.extend_from_slice(&3u32.to_le_bytes()); // Authentication method
             response.extend_from_slice(&0u16.to_le_bytes()); // Steam2 Encryption Key
@@ -97,8 +154,10 @@ pub async fn handle_stateless(
         }
         _ => {}
     }
-    if response.len() > 0 {
-        sock.send_to(&response, addr).await?;
+    if !response.is_empty() {
+        for packet in split_packet(response) {
+            sock.send_to(&packet, addr).await?;
+        }
     }
     Ok(())
 }